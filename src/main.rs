@@ -2,15 +2,43 @@ mod modules;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use modules::clipboard::ClipboardMonitor;
-use modules::sync::{ClipboardContent, ClipboardMessage, SyncClient, SyncServer};
+use modules::auth::AuthToken;
+use modules::clipboard::{
+    files, BackendKind, ClipboardMonitor, ClipboardTarget, ImageLimits, MemoryBackend,
+};
+use modules::config::{Config, Filters, Profile};
+use modules::crypto::ContentCipher;
+use modules::discovery;
+use modules::history::{self, HistoryLimits, HistoryStore};
+use modules::identity::Identity;
+use modules::mesh::SeenMessages;
+use modules::sync::{
+    ClipboardContent, ClipboardMessage, Endpoint, Heartbeat, Outbox, Selection, SyncClient,
+    SyncServer, HTML_MIME, PNG_MIME, TEXT_MIME,
+};
+use modules::tls;
+use modules::watcher;
+use modules::websocket::WebSocketUrl;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::sync::{broadcast, mpsc};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 #[derive(Parser)]
 #[command(name = "copi")]
 #[command(about = "A cross-platform clipboard synchronization tool", long_about = None)]
 struct Cli {
+    /// 配置文件路径（默认 ~/.config/copi/config.toml）
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// 使用配置文件中的指定 profile
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -18,39 +46,856 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Server {
-        #[arg(short, long, default_value = "0.0.0.0:9527")]
-        addr: SocketAddr,
+        /// 监听地址（默认 0.0.0.0:9527）
+        #[arg(short, long)]
+        addr: Option<SocketAddr>,
+
+        /// 额外在该地址接受 WebSocket 连接（启用 TLS 时为 wss）
+        #[arg(long)]
+        websocket: Option<SocketAddr>,
 
         /// 只转发模式：不访问剪贴板，仅在客户端之间转发数据（适用于无图形界面的服务器）
         #[arg(short, long)]
         relay_only: bool,
+
+        /// 启用 TLS 加密传输（未指定证书时首次运行自动生成自签名证书）
+        #[arg(long)]
+        tls: bool,
+
+        /// TLS 证书路径（PEM 格式）
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// TLS 私钥路径（PEM 格式）
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// 端到端加密口令，需要与客户端一致（只转发模式下无需设置）
+        #[arg(long, conflicts_with_all = ["relay_only", "passphrase_file"])]
+        passphrase: Option<String>,
+
+        /// 从文件读取端到端加密口令
+        #[arg(long, conflicts_with = "relay_only")]
+        passphrase_file: Option<PathBuf>,
+
+        /// 连接认证令牌，客户端必须持有相同的令牌才能连接
+        #[arg(long, conflicts_with = "token_file")]
+        token: Option<String>,
+
+        /// 从文件读取连接认证令牌
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+
+        /// 剪贴板后端（默认 auto）
+        #[arg(long, value_enum, conflicts_with = "relay_only")]
+        backend: Option<BackendKind>,
+
+        /// 同时同步 PRIMARY 选区（Linux 上选中即复制、鼠标中键粘贴）
+        #[arg(long, conflicts_with = "relay_only")]
+        primary: bool,
+
+        /// 收到的剪贴板内容写入本地的哪个选区（默认 clipboard）
+        #[arg(long, value_enum, conflicts_with = "relay_only")]
+        clipboard_target: Option<ClipboardTarget>,
+
+        /// 保存其他设备复制的文件的目录（默认 ~/Downloads/copi）
+        #[arg(long, conflicts_with = "relay_only")]
+        receive_dir: Option<PathBuf>,
+
+        /// 不记录剪贴板历史
+        #[arg(long)]
+        no_history: bool,
+
+        /// 只转发模式下也记录转发的剪贴板内容（默认不记录，未加密的内容会以明文保存在服务器上）
+        #[arg(long, conflicts_with = "no_history")]
+        relay_history: bool,
+
+        /// 新客户端连接时不发送当前剪贴板内容
+        #[arg(long)]
+        no_replay: bool,
+
+        /// 局域网自动发现使用的分组名称（默认 default）
+        #[arg(long)]
+        group: Option<String>,
+
+        /// 不在局域网中通过 mDNS 广播服务器
+        #[arg(long)]
+        no_mdns: bool,
+
+        /// 为房间设置单独的认证令牌，格式为 房间=令牌（可重复指定）
+        #[arg(long, value_name = "ROOM=TOKEN")]
+        room_token: Vec<String>,
+
+        /// 心跳间隔（秒，默认 5），0 表示关闭心跳
+        #[arg(long)]
+        heartbeat_interval: Option<u64>,
+
+        /// 超过该时间（秒，默认 15）没有收到对方任何数据时断开连接
+        #[arg(long)]
+        heartbeat_timeout: Option<u64>,
+
+        /// 等待客户端 hello 的时间（毫秒，默认 3000），超时后按旧版客户端处理，旧版客户端连接后要等这么久才收到内容
+        #[arg(long)]
+        hello_timeout_ms: Option<u64>,
+
+        /// 设备名称，显示在日志和剪贴板历史中（默认使用主机名）
+        #[arg(long)]
+        name: Option<String>,
     },
     Client {
+        /// 服务器地址，不指定时通过 mDNS 在局域网中查找服务器
+        #[arg(short, long, conflicts_with_all = ["peer", "p2p"])]
+        server: Option<SocketAddr>,
+
+        /// 通过 WebSocket 连接服务器，例如 wss://example.com:8443/（遵循 HTTPS_PROXY / HTTP_PROXY）
+        #[arg(long, conflicts_with_all = ["server", "group", "peer", "p2p"])]
+        url: Option<String>,
+
+        /// 局域网自动发现时使用的分组名称，需要与服务器一致（默认 default）
+        #[arg(long, conflicts_with = "server")]
+        group: Option<String>,
+
+        /// 加入服务器上的指定房间，只与同一房间的客户端同步
+        #[arg(long, conflicts_with_all = ["peer", "p2p"])]
+        room: Option<String>,
+
+        /// 房间的认证令牌，需要与服务器为该房间设置的一致
+        #[arg(long)]
+        room_token: Option<String>,
+
+        /// 点对点模式：不使用服务器，直接与其他客户端同步
+        #[arg(long)]
+        p2p: bool,
+
+        /// 点对点模式下接受其他客户端连接的地址（默认 0.0.0.0:9528，需要 --p2p 或 --peer）
+        #[arg(short, long, conflicts_with_all = ["server", "url"])]
+        listen: Option<SocketAddr>,
+
+        /// 点对点模式下主动连接的客户端地址（可重复指定，隐含 --p2p）
+        #[arg(long)]
+        peer: Vec<SocketAddr>,
+
+        /// 启用 TLS 加密传输
+        #[arg(long)]
+        tls: bool,
+
+        /// 点对点模式下监听使用的 TLS 证书路径（PEM 格式）
+        #[arg(long, requires = "tls_key", conflicts_with = "server")]
+        tls_cert: Option<PathBuf>,
+
+        /// 点对点模式下监听使用的 TLS 私钥路径（PEM 格式）
+        #[arg(long, requires = "tls_cert", conflicts_with = "server")]
+        tls_key: Option<PathBuf>,
+
+        /// 用于校验服务器证书的 CA 证书路径（PEM 格式）
+        #[arg(long)]
+        tls_ca: Option<PathBuf>,
+
+        /// 固定服务器证书的 SHA-256 指纹（服务器启动时会打印）
+        #[arg(long)]
+        tls_fingerprint: Option<String>,
+
+        /// 校验证书时使用的服务器名称，默认使用服务器 IP
+        #[arg(long)]
+        tls_server_name: Option<String>,
+
+        /// 端到端加密口令，所有客户端必须一致，服务器无法读取内容
+        #[arg(long, conflicts_with = "passphrase_file")]
+        passphrase: Option<String>,
+
+        /// 从文件读取端到端加密口令
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+
+        /// 连接认证令牌，需要与服务器一致
+        #[arg(long, conflicts_with = "token_file")]
+        token: Option<String>,
+
+        /// 从文件读取连接认证令牌
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+
+        /// 剪贴板后端（默认 auto）
+        #[arg(long, value_enum)]
+        backend: Option<BackendKind>,
+
+        /// 同时同步 PRIMARY 选区（Linux 上选中即复制、鼠标中键粘贴）
+        #[arg(long)]
+        primary: bool,
+
+        /// 收到的剪贴板内容写入本地的哪个选区（默认 clipboard）
+        #[arg(long, value_enum)]
+        clipboard_target: Option<ClipboardTarget>,
+
+        /// 保存其他设备复制的文件的目录（默认 ~/Downloads/copi）
+        #[arg(long)]
+        receive_dir: Option<PathBuf>,
+
+        /// 不记录剪贴板历史
+        #[arg(long)]
+        no_history: bool,
+
+        /// 心跳间隔（秒，默认 5），0 表示关闭心跳
+        #[arg(long)]
+        heartbeat_interval: Option<u64>,
+
+        /// 超过该时间（秒，默认 15）没有收到对方任何数据时断开连接
+        #[arg(long)]
+        heartbeat_timeout: Option<u64>,
+
+        /// 设备名称，显示在日志和剪贴板历史中（默认使用主机名）
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// 查看和恢复剪贴板历史
+    History {
+        #[command(subcommand)]
+        action: HistoryAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryAction {
+    /// 列出最近的历史记录
+    List {
+        /// 显示条数
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// 显示一条历史记录的完整内容
+    Show {
+        id: u64,
+
+        /// 将图片保存为 PNG 文件
         #[arg(short, long)]
-        server: SocketAddr,
+        output: Option<PathBuf>,
+    },
+    /// 搜索文本内容（不区分大小写）
+    Search { query: String },
+    /// 将历史记录恢复到本地剪贴板，正在运行的 copi 会将其同步出去
+    Restore {
+        id: u64,
 
-        #[arg(short, long, default_value = "0.0.0.0:9528")]
-        listen: SocketAddr,
+        /// 剪贴板后端（默认 auto）
+        #[arg(long, value_enum)]
+        backend: Option<BackendKind>,
     },
+    /// 清空历史记录
+    Clear,
+}
+
+// How the local clipboard is accessed and what of it is synced
+struct ClipboardOptions {
+    backend: BackendKind,
+    // Memory clipboard the caller keeps a handle to, used instead of a new backend
+    memory: Option<MemoryBackend>,
+    poll_interval: Duration,
+    image_limits: ImageLimits,
+    primary: bool,
+    clipboard_target: ClipboardTarget,
+    receive_dir: PathBuf,
+    max_files_size: usize,
+    filters: Filters,
+}
+
+impl ClipboardOptions {
+    fn new(
+        backend: Option<BackendKind>,
+        primary: bool,
+        clipboard_target: Option<ClipboardTarget>,
+        receive_dir: Option<PathBuf>,
+        profile: &Profile,
+    ) -> Self {
+        Self {
+            backend: backend.or(profile.backend).unwrap_or(BackendKind::Auto),
+            memory: None,
+            poll_interval: profile
+                .poll_interval()
+                .unwrap_or(watcher::DEFAULT_POLL_INTERVAL),
+            image_limits: profile.limits.image(),
+            primary: primary || profile.primary.unwrap_or(false),
+            clipboard_target: clipboard_target
+                .or(profile.clipboard_target)
+                .unwrap_or_default(),
+            receive_dir: receive_dir
+                .or(profile.receive_dir.clone())
+                .unwrap_or_else(files::default_receive_dir),
+            max_files_size: profile.limits.max_files_size(),
+            filters: profile.filters.clone(),
+        }
+    }
+
+    fn monitor(&self) -> Result<ClipboardMonitor> {
+        let monitor = match &self.memory {
+            Some(memory) => ClipboardMonitor::with_backend(Box::new(memory.clone())),
+            None => ClipboardMonitor::from_kind(self.backend)?,
+        };
+        Ok(monitor
+            .with_poll_interval(self.poll_interval)
+            .with_image_limits(self.image_limits)
+            .with_primary(self.primary)
+            .with_clipboard_target(self.clipboard_target)
+            .with_receive_dir(self.receive_dir.clone())
+            .with_max_files_size(self.max_files_size))
+    }
+}
+
+struct ServerOptions {
+    addr: SocketAddr,
+    websocket: Option<SocketAddr>,
+    relay_only: bool,
+    clipboard: ClipboardOptions,
+    room_tokens: Vec<(String, AuthToken)>,
+    replay: bool,
+    // mDNS group to advertise, None disables advertising
+    group: Option<String>,
+    heartbeat: Option<Heartbeat>,
+    // None keeps the server's default
+    hello_timeout: Option<Duration>,
+    identity: Identity,
+}
+
+struct PeerOptions {
+    listen: SocketAddr,
+    peers: Vec<SocketAddr>,
+    clipboard: ClipboardOptions,
+}
+
+struct ServerTlsOptions {
+    enabled: bool,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+struct ClientTlsOptions {
+    enabled: bool,
+    ca: Option<PathBuf>,
+    fingerprint: Option<String>,
+    server_name: Option<String>,
+}
+
+// 口令可以直接在命令行给出，也可以从文件读取（避免出现在进程列表中）
+fn read_secret(value: Option<String>, file: Option<PathBuf>) -> Result<Option<String>> {
+    match (value, file) {
+        (Some(value), _) => Ok(Some(value)),
+        (None, Some(path)) => {
+            let secret = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+            Ok(Some(secret.trim().to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+// 命令行给出的口令（或口令文件）优先于配置文件中的
+fn read_secret_or(
+    value: Option<String>,
+    file: Option<PathBuf>,
+    profile_value: Option<String>,
+    profile_file: Option<PathBuf>,
+) -> Result<Option<String>> {
+    if value.is_some() || file.is_some() {
+        read_secret(value, file)
+    } else {
+        read_secret(profile_value, profile_file)
+    }
+}
+
+fn build_cipher(passphrase: Option<String>) -> Result<Option<ContentCipher>> {
+    match passphrase {
+        Some(passphrase) if passphrase.is_empty() => anyhow::bail!("Passphrase must not be empty"),
+        Some(passphrase) => {
+            println!("End-to-end encryption enabled");
+            Ok(Some(ContentCipher::from_passphrase(&passphrase)?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn build_token(token: Option<String>) -> Result<Option<AuthToken>> {
+    token.map(|token| AuthToken::new(&token)).transpose()
+}
+
+// 命令行中的 房间=令牌 与配置文件中的 room_tokens 合并，命令行优先
+fn build_room_tokens(
+    args: Vec<String>,
+    profile: &HashMap<String, String>,
+) -> Result<Vec<(String, AuthToken)>> {
+    let mut tokens = profile.clone();
+    for arg in args {
+        let (room, token) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("--room-token expects ROOM=TOKEN, got '{}'", arg))?;
+        tokens.insert(room.to_string(), token.to_string());
+    }
+    tokens
+        .into_iter()
+        .map(|(room, token)| Ok((room, AuthToken::new(&token)?)))
+        .collect()
+}
+
+fn build_heartbeat(interval: u64, timeout: u64) -> Result<Option<Heartbeat>> {
+    if interval == 0 {
+        return Ok(None);
+    }
+    if timeout <= interval {
+        anyhow::bail!("--heartbeat-timeout must be longer than --heartbeat-interval");
+    }
+    Ok(Some(Heartbeat {
+        interval: Duration::from_secs(interval),
+        timeout: Duration::from_secs(timeout),
+    }))
+}
+
+fn build_acceptor(tls: &ServerTlsOptions) -> Result<Option<TlsAcceptor>> {
+    if !tls.enabled {
+        return Ok(None);
+    }
+    let (acceptor, fingerprint) = tls::server_acceptor(tls.cert.as_deref(), tls.key.as_deref())?;
+    println!("TLS enabled, certificate fingerprint (SHA-256):");
+    println!("  {}", fingerprint);
+    Ok(Some(acceptor))
+}
+
+// Where a client connects to: a fixed address, a WebSocket URL, or the server mDNS
+// finds for a group
+enum ServerTarget {
+    Addr(SocketAddr),
+    WebSocket(WebSocketUrl),
+    Discover(String),
+}
+
+impl ServerTarget {
+    // `tls` is whether the client was set up for TLS
+    async fn resolve(&self, tls: bool) -> Result<Endpoint> {
+        match self {
+            ServerTarget::Addr(addr) => Ok(Endpoint::Tcp(*addr)),
+            ServerTarget::WebSocket(url) => Ok(Endpoint::WebSocket(url.clone())),
+            ServerTarget::Discover(group) => {
+                let server = discovery::discover(group).await?;
+                // Anyone on the LAN can announce a server, so the announcement can't be
+                // what the certificate is trusted for; the user has to pin it
+                if server.tls && !tls {
+                    anyhow::bail!(
+                        "Server at {} uses TLS, connect with --tls-fingerprint <fingerprint> (printed by the server) or --tls-ca <file>",
+                        server.addr
+                    );
+                }
+                if !server.tls && tls {
+                    anyhow::bail!(
+                        "Server at {} doesn't use TLS, refusing to connect without it",
+                        server.addr
+                    );
+                }
+                Ok(Endpoint::Tcp(server.addr))
+            }
+        }
+    }
+}
+
+// Everything needed to build a SyncClient once the address is known
+#[derive(Clone)]
+struct ConnectionSettings {
+    connector: Option<TlsConnector>,
+    server_name: Option<String>,
+    cipher: Option<ContentCipher>,
+    token: Option<AuthToken>,
+    heartbeat: Option<Heartbeat>,
+    identity: Identity,
+    retry_delay: Duration,
+    room: Option<String>,
+    room_token: Option<AuthToken>,
+}
+
+impl ConnectionSettings {
+    fn new(
+        tls: ClientTlsOptions,
+        cipher: Option<ContentCipher>,
+        token: Option<AuthToken>,
+        heartbeat: Option<Heartbeat>,
+        identity: Identity,
+        retry_delay: Duration,
+    ) -> Result<Self> {
+        let connector = if tls.enabled {
+            println!("TLS enabled");
+            Some(tls::client_connector(
+                tls.ca.as_deref(),
+                tls.fingerprint.as_deref(),
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            connector,
+            server_name: tls.server_name,
+            cipher,
+            token,
+            heartbeat,
+            identity,
+            retry_delay,
+            room: None,
+            room_token: None,
+        })
+    }
+
+    fn with_room(mut self, room: Option<String>, token: Option<AuthToken>) -> Self {
+        self.room = room;
+        self.room_token = token;
+        self
+    }
+
+    fn client(&self, endpoint: Endpoint) -> Result<SyncClient> {
+        let server_name = match (&self.server_name, &endpoint) {
+            (Some(name), _) => ServerName::try_from(name.clone())?,
+            (None, Endpoint::Tcp(addr)) => ServerName::IpAddress(addr.ip().into()),
+            (None, Endpoint::WebSocket(url)) => {
+                ServerName::try_from(url.server_name().to_string())?
+            }
+        };
+        let mut client = SyncClient::new(endpoint)
+            .with_heartbeat(self.heartbeat)
+            .with_identity(&self.identity);
+
+        if let Some(connector) = &self.connector {
+            client = client.with_tls(connector.clone(), server_name);
+        }
+
+        if let Some(cipher) = &self.cipher {
+            client = client.with_encryption(cipher.clone());
+        }
+
+        if let Some(token) = &self.token {
+            client = client.with_token(token.clone());
+        }
+
+        if let Some(room) = &self.room {
+            client = client.with_room(room.clone(), self.room_token.clone());
+        }
+
+        Ok(client)
+    }
+}
+
+// Keep a connection open, reconnecting (and re-discovering the server) after errors
+fn spawn_connection(
+    target: ServerTarget,
+    settings: ConnectionSettings,
+    from_server_tx: mpsc::UnboundedSender<ClipboardMessage>,
+    to_server_tx: broadcast::Sender<ClipboardMessage>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        // Outlives the connections, so unacknowledged messages survive a reconnect
+        let outbox = Outbox::new();
+        loop {
+            let to_server_rx = to_server_tx.subscribe();
+            let result: Result<()> = async {
+                let client = settings
+                    .client(target.resolve(settings.connector.is_some()).await?)?
+                    .with_outbox(outbox.clone());
+                client
+                    .connect_bidirectional(from_server_tx.clone(), to_server_rx)
+                    .await
+            }
+            .await;
+            match result {
+                Ok(_) => {
+                    println!("Connection closed, reconnecting...");
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Connection error: {}, retrying in {}s...",
+                        e,
+                        settings.retry_delay.as_secs()
+                    );
+                }
+            }
+            tokio::time::sleep(settings.retry_delay).await;
+        }
+    })
+}
+
+fn open_history(limits: HistoryLimits) -> Result<HistoryStore> {
+    HistoryStore::open(&HistoryStore::default_path()?, limits)
+}
+
+// 历史记录不可用时只打印错误，不影响同步
+fn open_history_for_sync(disabled: bool, limits: HistoryLimits) -> Option<HistoryStore> {
+    if disabled {
+        return None;
+    }
+    match open_history(limits) {
+        Ok(history) => Some(history),
+        Err(e) => {
+            eprintln!("Clipboard history disabled: {}", e);
+            None
+        }
+    }
+}
+
+fn record_history(
+    history: &mut Option<HistoryStore>,
+    selection: Selection,
+    content: &ClipboardContent,
+    client_id: Option<&str>,
+    device_name: Option<&str>,
+) {
+    // PRIMARY changes with every mouse selection, only copies are worth keeping
+    if selection == Selection::Primary {
+        return;
+    }
+    if let Some(history) = history {
+        if let Err(e) = history.record(content, client_id, device_name) {
+            eprintln!("Failed to record clipboard history: {}", e);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref())?;
+    let profile = config.profile(cli.profile.as_deref())?;
 
     match cli.command {
-        Commands::Server { addr, relay_only } => {
-            run_server(addr, relay_only).await?;
+        Commands::Server {
+            addr,
+            websocket,
+            relay_only,
+            tls,
+            tls_cert,
+            tls_key,
+            passphrase,
+            passphrase_file,
+            token,
+            token_file,
+            backend,
+            primary,
+            clipboard_target,
+            receive_dir,
+            no_history,
+            relay_history,
+            no_replay,
+            group,
+            no_mdns,
+            room_token,
+            heartbeat_interval,
+            heartbeat_timeout,
+            hello_timeout_ms,
+            name,
+        } => {
+            let tls_cert = tls_cert.or(profile.tls_cert.clone());
+            let tls = ServerTlsOptions {
+                enabled: tls || profile.tls.unwrap_or(false) || tls_cert.is_some(),
+                cert: tls_cert,
+                key: tls_key.or(profile.tls_key.clone()),
+            };
+            let cipher = build_cipher(read_secret_or(
+                passphrase,
+                passphrase_file,
+                profile.passphrase.clone(),
+                profile.passphrase_file.clone(),
+            )?)?;
+            let token = build_token(read_secret_or(
+                token,
+                token_file,
+                profile.token.clone(),
+                profile.token_file.clone(),
+            )?)?;
+            let relay_only = relay_only || profile.relay_only.unwrap_or(false);
+            // A relay sees other people's clipboards, it only keeps them when asked to
+            let history = open_history_for_sync(
+                no_history
+                    || !profile.history.unwrap_or(true)
+                    || (relay_only && !relay_history && !profile.relay_history.unwrap_or(false)),
+                profile.limits.history(),
+            );
+            let group = group
+                .or(profile.group.clone())
+                .unwrap_or_else(|| discovery::DEFAULT_GROUP.to_string());
+            let mdns = !no_mdns && profile.mdns.unwrap_or(true);
+            let options = ServerOptions {
+                addr: addr
+                    .or(profile.addr)
+                    .unwrap_or_else(|| "0.0.0.0:9527".parse().unwrap()),
+                websocket: websocket.or(profile.websocket),
+                relay_only,
+                clipboard: ClipboardOptions::new(
+                    backend,
+                    primary,
+                    clipboard_target,
+                    receive_dir,
+                    &profile,
+                ),
+                room_tokens: build_room_tokens(
+                    room_token,
+                    profile.room_tokens.as_ref().unwrap_or(&HashMap::new()),
+                )?,
+                replay: !no_replay && profile.replay.unwrap_or(true),
+                group: mdns.then_some(group),
+                heartbeat: build_heartbeat(
+                    heartbeat_interval
+                        .or(profile.heartbeat_interval)
+                        .unwrap_or(5),
+                    heartbeat_timeout
+                        .or(profile.heartbeat_timeout)
+                        .unwrap_or(15),
+                )?,
+                hello_timeout: hello_timeout_ms
+                    .map(Duration::from_millis)
+                    .or(profile.hello_timeout()),
+                identity: Identity::load(name.or(profile.name.clone()))?,
+            };
+            run_server(options, tls, cipher, token, history).await?;
         }
-        Commands::Client { server, listen } => {
-            run_client(server, listen).await?;
+        Commands::Client {
+            server,
+            url,
+            group,
+            room,
+            room_token,
+            p2p,
+            listen,
+            peer,
+            tls,
+            tls_cert,
+            tls_key,
+            tls_ca,
+            tls_fingerprint,
+            tls_server_name,
+            passphrase,
+            passphrase_file,
+            token,
+            token_file,
+            backend,
+            primary,
+            clipboard_target,
+            receive_dir,
+            no_history,
+            heartbeat_interval,
+            heartbeat_timeout,
+            name,
+        } => {
+            // 命令行给出的 --server 优先于配置文件中的 url
+            let url = match server {
+                Some(_) => None,
+                None => url.or(profile.url.clone()),
+            };
+            let url = url.map(|url| WebSocketUrl::parse(&url)).transpose()?;
+            let tls_cert = tls_cert.or(profile.tls_cert.clone());
+            let tls_ca = tls_ca.or(profile.tls_ca.clone());
+            let tls_fingerprint = tls_fingerprint.or(profile.tls_fingerprint.clone());
+            let tls_server_name = tls_server_name.or(profile.tls_server_name.clone());
+            let tls = ClientTlsOptions {
+                enabled: tls
+                    || profile.tls.unwrap_or(false)
+                    || url.as_ref().is_some_and(WebSocketUrl::is_secure)
+                    || tls_cert.is_some()
+                    || tls_ca.is_some()
+                    || tls_fingerprint.is_some()
+                    || tls_server_name.is_some(),
+                ca: tls_ca,
+                fingerprint: tls_fingerprint,
+                server_name: tls_server_name,
+            };
+            let server_tls = ServerTlsOptions {
+                enabled: tls.enabled,
+                cert: tls_cert,
+                key: tls_key.or(profile.tls_key.clone()),
+            };
+            let cipher = build_cipher(read_secret_or(
+                passphrase,
+                passphrase_file,
+                profile.passphrase.clone(),
+                profile.passphrase_file.clone(),
+            )?)?;
+            let token = build_token(read_secret_or(
+                token,
+                token_file,
+                profile.token.clone(),
+                profile.token_file.clone(),
+            )?)?;
+            let heartbeat = build_heartbeat(
+                heartbeat_interval
+                    .or(profile.heartbeat_interval)
+                    .unwrap_or(5),
+                heartbeat_timeout
+                    .or(profile.heartbeat_timeout)
+                    .unwrap_or(15),
+            )?;
+            let identity = Identity::load(name.or(profile.name.clone()))?;
+            let retry_delay = profile.retry_delay().unwrap_or(Duration::from_secs(5));
+            let room_token = build_token(room_token.or(profile.room_token.clone()))?;
+            let settings =
+                ConnectionSettings::new(tls, cipher, token, heartbeat, identity, retry_delay)?
+                    .with_room(room.or(profile.room.clone()), room_token);
+            let history = open_history_for_sync(
+                no_history || !profile.history.unwrap_or(true),
+                profile.limits.history(),
+            );
+            let clipboard =
+                ClipboardOptions::new(backend, primary, clipboard_target, receive_dir, &profile);
+            // 命令行指定了服务器时忽略配置文件中的点对点设置
+            let peers = if server.is_none() && url.is_none() && peer.is_empty() {
+                profile.peers.clone().unwrap_or_default()
+            } else {
+                peer
+            };
+            let p2p = p2p || (server.is_none() && url.is_none() && profile.p2p.unwrap_or(false));
+            if p2p || !peers.is_empty() {
+                let options = PeerOptions {
+                    listen: listen
+                        .or(profile.listen)
+                        .unwrap_or_else(|| "0.0.0.0:9528".parse().unwrap()),
+                    peers,
+                    clipboard,
+                };
+                run_peer(options, server_tls, settings, history).await?;
+            } else {
+                if listen.is_some() {
+                    eprintln!(
+                        "--listen only applies in peer-to-peer mode (--p2p or --peer), ignoring it"
+                    );
+                }
+                let target = match (server, url, profile.server) {
+                    (Some(server), _, _) => ServerTarget::Addr(server),
+                    (None, Some(url), _) => ServerTarget::WebSocket(url),
+                    (None, None, Some(server)) => ServerTarget::Addr(server),
+                    (None, None, None) => ServerTarget::Discover(
+                        group
+                            .or(profile.group.clone())
+                            .unwrap_or_else(|| discovery::DEFAULT_GROUP.to_string()),
+                    ),
+                };
+                run_client(target, settings, clipboard, history).await?;
+            }
         }
+        Commands::History { action } => run_history(action, &profile)?,
     }
 
     Ok(())
 }
 
-async fn run_server(addr: SocketAddr, relay_only: bool) -> Result<()> {
+async fn run_server(
+    options: ServerOptions,
+    tls: ServerTlsOptions,
+    cipher: Option<ContentCipher>,
+    token: Option<AuthToken>,
+    mut history: Option<HistoryStore>,
+) -> Result<()> {
+    let ServerOptions {
+        addr,
+        websocket,
+        relay_only,
+        clipboard: clipboard_options,
+        room_tokens,
+        replay,
+        group,
+        heartbeat,
+        hello_timeout,
+        identity,
+    } = options;
+
     println!("Starting clipboard sync server...");
     println!("Platform: {}", std::env::consts::OS);
 
@@ -61,7 +906,37 @@ async fn run_server(addr: SocketAddr, relay_only: bool) -> Result<()> {
     let (tx, mut rx) = mpsc::unbounded_channel();
     let (broadcast_tx, _) = broadcast::channel::<ClipboardMessage>(100);
 
-    let server = SyncServer::new(addr, tx.clone(), broadcast_tx.clone());
+    let mut server = SyncServer::new(addr, tx.clone(), broadcast_tx.clone())
+        .with_replay(replay)
+        .with_heartbeat(heartbeat)
+        .with_identity(&identity);
+    if let Some(timeout) = hello_timeout {
+        server = server.with_hello_timeout(timeout);
+    }
+
+    if let Some(acceptor) = build_acceptor(&tls)? {
+        server = server.with_tls(acceptor);
+    }
+
+    if let Some(token) = token {
+        println!("Token authentication enabled");
+        server = server.with_token(token);
+    }
+
+    if let Some(websocket) = websocket {
+        server = server.with_websocket(websocket);
+    }
+
+    for (room, token) in room_tokens {
+        println!("Room {} requires its own token", room);
+        server = server.with_room_token(room, token);
+    }
+
+    if let Some(group) = group {
+        server = server.with_discovery(group);
+    }
+
+    let rooms = server.rooms();
 
     let server_handle = tokio::spawn(async move {
         if let Err(e) = server.start().await {
@@ -73,19 +948,21 @@ async fn run_server(addr: SocketAddr, relay_only: bool) -> Result<()> {
         // 只转发模式：只接收来自客户端的消息并转发，不访问剪贴板
         let receive_handle = tokio::spawn(async move {
             while let Some(message) = rx.recv().await {
-                match &message.content {
-                    ClipboardContent::Text(text) => {
-                        println!("Received clipboard content from client: text ({} bytes), relaying to other clients...", text.len());
-                    }
-                    ClipboardContent::Image { width, height, .. } => {
-                        println!("Received clipboard content from client: image ({}x{}), relaying to other clients...", width, height);
-                    }
-                    ClipboardContent::Html { html, .. } => {
-                        println!("Received clipboard content from client: html ({} bytes), relaying to other clients...", html.len());
-                    }
-                }
-                // 在只转发模式下，通过 broadcast 发送给其他客户端（保留 client_id）
-                if let Err(e) = broadcast_tx.send(message) {
+                println!(
+                    "Received clipboard content from {}: {}, relaying to other clients...",
+                    message.sender(),
+                    message.summary()
+                );
+                // 端到端加密的内容不会被记录
+                record_history(
+                    &mut history,
+                    message.selection,
+                    &message.content,
+                    message.client_id.as_deref(),
+                    message.device_name.as_deref(),
+                );
+                // 在只转发模式下，通过 broadcast 发送给同一房间的其他客户端（不会发回给发送它的连接）
+                if let Err(e) = rooms.send(message) {
                     eprintln!("Failed to broadcast: {}", e);
                 }
             }
@@ -96,7 +973,7 @@ async fn run_server(addr: SocketAddr, relay_only: bool) -> Result<()> {
         // 正常模式：访问剪贴板
         // 统一的剪贴板管理任务，避免重复广播
         let clipboard_handle = tokio::spawn(async move {
-            let mut clipboard = match ClipboardMonitor::new() {
+            let mut clipboard = match clipboard_options.monitor() {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("Failed to create clipboard monitor: {}", e);
@@ -106,73 +983,102 @@ async fn run_server(addr: SocketAddr, relay_only: bool) -> Result<()> {
 
             let (local_tx, mut local_rx) = mpsc::unbounded_channel();
 
-            // 剪贴板监控任务
-            let monitor_handle = {
-                let local_tx = local_tx.clone();
-                tokio::spawn(async move {
-                    loop {
-                        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-                        if let Err(e) = local_tx.send(()) {
-                            eprintln!("Monitor channel closed: {}", e);
-                            break;
-                        }
-                    }
-                })
-            };
+            // 剪贴板监控任务（优先使用变化事件，不支持时回退到轮询）
+            let monitor_handle = clipboard.watch(local_tx.clone());
 
             loop {
                 tokio::select! {
                     // 检查本地剪贴板变化
                     Some(_) = local_rx.recv() => {
-                        if let Ok(Some(content)) = clipboard.get_clipboard_content() {
-                            match &content {
-                                ClipboardContent::Text(text) => {
-                                    println!("Server clipboard changed: text ({} bytes), broadcasting to clients...", text.len());
-                                }
-                                ClipboardContent::Image { width, height, .. } => {
-                                    println!("Server clipboard changed: image ({}x{}), broadcasting to clients...", width, height);
-                                }
-                                ClipboardContent::Html { html, .. } => {
-                                    println!("Server clipboard changed: html ({} bytes), broadcasting to clients...", html.len());
+                        for (selection, content) in clipboard.changes() {
+                            let summary = content.summary();
+                            let Some(content) = clipboard_options.filters.filter(content) else {
+                                println!("Server {} changed: {}, filtered out", selection, summary);
+                                continue;
+                            };
+                            println!(
+                                "Server {} changed: {}, broadcasting to clients...",
+                                selection,
+                                content.summary()
+                            );
+                            record_history(&mut history, selection, &content, None, Some(&identity.name));
+                            // 服务器本地的剪贴板变化以设备 id 作为来源，时钟相同时用来决定先后
+                            let mut message = clipboard.stamp(
+                                ClipboardMessage::new(content, Some(identity.device_id.clone()))
+                                    .with_device_name(&identity.name)
+                                    .with_selection(selection),
+                            );
+                            // 设置了口令时，广播前先加密（时钟已经盖上，会绑定到密文）
+                            if let Some(cipher) = &cipher {
+                                if let Err(e) = cipher.seal(&mut message, None) {
+                                    eprintln!("Failed to encrypt clipboard content: {}", e);
+                                    continue;
                                 }
                             }
-                            let message = ClipboardMessage {
-                                content,
-                                timestamp: std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs(),
-                                client_id: None, // 服务器本地的剪贴板变化没有 client_id
-                            };
                             if let Err(e) = broadcast_tx.send(message) {
                                 eprintln!("Failed to broadcast: {}", e);
                             }
                         }
                     }
                     // 接收来自客户端的消息
-                    Some(message) = rx.recv() => {
-                        match &message.content {
-                            ClipboardContent::Text(text) => {
-                                println!(
-                                    "Received clipboard content from client: text ({} bytes)",
-                                    text.len()
-                                );
-                            }
-                            ClipboardContent::Image { width, height, .. } => {
-                                println!(
-                                    "Received clipboard content from client: image ({}x{})",
-                                    width, height
-                                );
+                    Some(mut message) = rx.recv() => {
+                        // 服务器剪贴板只属于共享房间，其他房间的消息只在房间内转发
+                        if let Some(room) = &message.room {
+                            println!(
+                                "Relaying clipboard content from {} in room {}: {}",
+                                message.sender(),
+                                room,
+                                message.summary()
+                            );
+                            if let Err(e) = rooms.send(message) {
+                                eprintln!("Failed to broadcast: {}", e);
                             }
-                            ClipboardContent::Html { html, .. } => {
-                                println!(
-                                    "Received clipboard content from client: html ({} bytes)",
-                                    html.len()
-                                );
+                            continue;
+                        }
+                        // 转发给其他客户端的是原始消息，设置了口令时仍是密文
+                        let forwarded = message.clone();
+                        if let Some(cipher) = &cipher {
+                            if let Err(e) = cipher.open(&mut message, None) {
+                                eprintln!("Rejected message from client: {}", e);
+                                continue;
                             }
                         }
+                        // 同时复制时以较新的为准，较旧的既不应用也不转发，所有设备最终一致
+                        if !clipboard.accept(&message) {
+                            println!(
+                                "Ignored clipboard content from {}, older than the current one: {}",
+                                message.sender(),
+                                message.summary()
+                            );
+                            continue;
+                        }
+                        println!(
+                            "Received clipboard content from {}: {}, forwarding to other clients...",
+                            message.sender(),
+                            message.summary()
+                        );
+                        // 和只转发模式一样，发给共享房间的其他客户端（不会发回给发送它的连接）
+                        if let Err(e) = rooms.send(forwarded) {
+                            eprintln!("Failed to broadcast: {}", e);
+                        }
+                        // 没有开启 --primary 时，PRIMARY 选区的消息只转发
+                        if !clipboard.syncs(message.selection) {
+                            continue;
+                        }
+                        // 过滤只决定服务器剪贴板是否应用，其他客户端有自己的过滤设置
+                        let Some(content) = clipboard_options.filters.filter(message.content) else {
+                            println!("Filtered out, not applied to the server clipboard");
+                            continue;
+                        };
+                        record_history(
+                            &mut history,
+                            message.selection,
+                            &content,
+                            message.client_id.as_deref(),
+                            message.device_name.as_deref(),
+                        );
                         // 更新服务器剪贴板（会同时更新 hash）
-                        if let Err(e) = clipboard.set_clipboard_content(&message.content) {
+                        if let Err(e) = clipboard.apply(message.selection, &content) {
                             eprintln!("Failed to set server clipboard: {}", e);
                         }
                     }
@@ -189,54 +1095,41 @@ async fn run_server(addr: SocketAddr, relay_only: bool) -> Result<()> {
     Ok(())
 }
 
-async fn run_client(server_addr: SocketAddr, _listen_addr: SocketAddr) -> Result<()> {
+async fn run_client(
+    target: ServerTarget,
+    settings: ConnectionSettings,
+    clipboard_options: ClipboardOptions,
+    mut history: Option<HistoryStore>,
+) -> Result<()> {
     println!("Starting clipboard sync client...");
     println!("Platform: {}", std::env::consts::OS);
-    println!("Connecting to server: {}", server_addr);
-
-    // Generate unique client ID
-    let client_id = format!(
-        "{}-{}",
-        std::env::consts::OS,
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_micros()
-    );
-    println!("Client ID: {}", client_id);
+    match &target {
+        ServerTarget::Addr(addr) => println!("Connecting to server: {}", addr),
+        ServerTarget::WebSocket(url) => println!("Connecting to server: {}", url),
+        ServerTarget::Discover(group) => {
+            println!(
+                "Looking for a server on the local network (group: {})",
+                group
+            )
+        }
+    }
+
+    let identity = settings.identity.clone();
+    println!("Device: {} ({})", identity.name, identity.device_id);
 
     // Channel for sending clipboard content to server (broadcast for reconnection support)
-    let (to_server_tx, _) = broadcast::channel::<ClipboardContent>(100);
+    let (to_server_tx, _) = broadcast::channel::<ClipboardMessage>(100);
     // Channel for receiving clipboard content from server
     let (from_server_tx, from_server_rx) = mpsc::unbounded_channel();
 
-    let client = SyncClient::new(server_addr, client_id.clone());
-
     // Task to maintain connection with server (bidirectional)
-    let to_server_for_connection = to_server_tx.clone();
-    let connection_handle = tokio::spawn(async move {
-        loop {
-            let to_server_rx = to_server_for_connection.subscribe();
-            match client
-                .connect_bidirectional(from_server_tx.clone(), to_server_rx)
-                .await
-            {
-                Ok(_) => {
-                    println!("Connection closed, reconnecting...");
-                }
-                Err(e) => {
-                    eprintln!("Connection error: {}, retrying in 5s...", e);
-                }
-            }
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-        }
-    });
+    let connection_handle =
+        spawn_connection(target, settings, from_server_tx, to_server_tx.clone());
 
     // Unified clipboard management task
     // This task handles both monitoring local changes and receiving from server
-    let client_id_for_clipboard = client_id.clone();
     let clipboard_handle = tokio::spawn(async move {
-        let mut clipboard = match ClipboardMonitor::new() {
+        let mut clipboard = match clipboard_options.monitor() {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Failed to create clipboard monitor: {}", e);
@@ -247,79 +1140,79 @@ async fn run_client(server_addr: SocketAddr, _listen_addr: SocketAddr) -> Result
         let (local_tx, mut local_rx) = mpsc::unbounded_channel();
         let mut from_server_rx = from_server_rx;
 
-        // Spawn clipboard monitoring task
-        let monitor_handle = {
-            let local_tx = local_tx.clone();
-            tokio::spawn(async move {
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-                    if let Err(e) = local_tx.send(()) {
-                        eprintln!("Monitor channel closed: {}", e);
-                        break;
-                    }
-                }
-            })
-        };
+        // Spawn clipboard monitoring task (change events, polling as fallback)
+        let monitor_handle = clipboard.watch(local_tx.clone());
 
         loop {
             tokio::select! {
                 // Check local clipboard changes
                 Some(_) = local_rx.recv() => {
-                    if let Ok(Some(content)) = clipboard.get_clipboard_content() {
-                        match &content {
-                            ClipboardContent::Text(text) => {
-                                println!(
-                                    "Local clipboard changed, sending to server: text ({} bytes)",
-                                    text.len()
-                                );
-                            }
-                            ClipboardContent::Image { width, height, .. } => {
-                                println!(
-                                    "Local clipboard changed, sending to server: image ({}x{})",
-                                    width, height
-                                );
-                            }
-                            ClipboardContent::Html { html, .. } => {
-                                println!(
-                                    "Local clipboard changed, sending to server: html ({} bytes)",
-                                    html.len()
-                                );
-                            }
-                        }
-                        if let Err(e) = to_server_tx.send(content) {
+                    for (selection, content) in clipboard.changes() {
+                        let summary = content.summary();
+                        let Some(content) = clipboard_options.filters.filter(content) else {
+                            println!("Local {} changed: {}, filtered out", selection, summary);
+                            continue;
+                        };
+                        println!(
+                            "Local {} changed, sending to server: {}",
+                            selection,
+                            content.summary()
+                        );
+                        record_history(
+                            &mut history,
+                            selection,
+                            &content,
+                            Some(&identity.device_id),
+                            Some(&identity.name),
+                        );
+                        let message = clipboard.stamp(
+                            ClipboardMessage::new(content, Some(identity.device_id.clone()))
+                                .with_device_name(&identity.name)
+                                .with_selection(selection),
+                        );
+                        if let Err(e) = to_server_tx.send(message) {
                             eprintln!("Failed to send to server: {}", e);
                         }
                     }
                 }
                 // Receive from server
                 Some(message) = from_server_rx.recv() => {
-                    // Skip messages from ourselves
-                    if message.client_id.as_ref() == Some(&client_id_for_clipboard) {
+                    // Current servers don't send our own messages back, legacy ones relay
+                    // them to every client
+                    if message.client_id.as_deref() == Some(identity.device_id.as_str()) {
                         continue;
                     }
-
-                    match &message.content {
-                        ClipboardContent::Text(text) => {
-                            println!(
-                                "Received clipboard from server: text ({} bytes)",
-                                text.len()
-                            );
-                        }
-                        ClipboardContent::Image { width, height, .. } => {
-                            println!(
-                                "Received clipboard from server: image ({}x{})",
-                                width, height
-                            );
-                        }
-                        ClipboardContent::Html { html, .. } => {
-                            println!(
-                                "Received clipboard from server: html ({} bytes)",
-                                html.len()
-                            );
-                        }
+                    // Other clients sync PRIMARY, we don't
+                    if !clipboard.syncs(message.selection) {
+                        continue;
+                    }
+                    // Concurrent copies: the newer one wins on every device
+                    if !clipboard.accept(&message) {
+                        println!(
+                            "Ignored {} from server, older than the current one: {}",
+                            message.selection,
+                            message.summary()
+                        );
+                        continue;
                     }
+                    println!(
+                        "Received {} from server: {}",
+                        message.selection,
+                        message.summary()
+                    );
+                    let Some(content) = clipboard_options.filters.filter(message.content) else {
+                        println!("Filtered out, not applied to the local clipboard");
+                        continue;
+                    };
+                    record_history(
+                        &mut history,
+                        message.selection,
+                        &content,
+                        message.client_id.as_deref(),
+                        message.device_name.as_deref(),
+                    );
                     // Update clipboard and hash together
-                    if let Err(e) = clipboard.set_clipboard_content(&message.content) {
+                    if let Err(e) = clipboard.apply(message.selection, &content) {
                         eprintln!("Failed to set clipboard: {}", e);
                     }
                 }
@@ -334,3 +1227,726 @@ async fn run_client(server_addr: SocketAddr, _listen_addr: SocketAddr) -> Result
 
     Ok(())
 }
+
+async fn run_peer(
+    options: PeerOptions,
+    server_tls: ServerTlsOptions,
+    settings: ConnectionSettings,
+    mut history: Option<HistoryStore>,
+) -> Result<()> {
+    let PeerOptions {
+        listen,
+        peers,
+        clipboard: clipboard_options,
+    } = options;
+
+    println!("Starting clipboard sync in peer-to-peer mode...");
+    println!("Platform: {}", std::env::consts::OS);
+
+    let identity = settings.identity.clone();
+    println!("Device: {} ({})", identity.name, identity.device_id);
+
+    // Peers that connect to us
+    let (from_inbound_tx, mut from_inbound_rx) = mpsc::unbounded_channel();
+    let (to_inbound_tx, _) = broadcast::channel::<ClipboardMessage>(100);
+    let mut server = SyncServer::new(listen, from_inbound_tx, to_inbound_tx.clone())
+        .with_heartbeat(settings.heartbeat)
+        .with_identity(&identity);
+    if let Some(acceptor) = build_acceptor(&server_tls)? {
+        server = server.with_tls(acceptor);
+    }
+    if let Some(token) = &settings.token {
+        println!("Token authentication enabled");
+        server = server.with_token(token.clone());
+    }
+    tokio::spawn(async move {
+        if let Err(e) = server.start().await {
+            eprintln!("Peer listener error: {}", e);
+        }
+    });
+
+    // Peers we connect to
+    let (from_outbound_tx, mut from_outbound_rx) = mpsc::unbounded_channel();
+    let (to_outbound_tx, _) = broadcast::channel::<ClipboardMessage>(100);
+    if peers.is_empty() {
+        println!("No peers given, waiting for peers to connect to {}", listen);
+    }
+    for peer in peers {
+        println!("Connecting to peer: {}", peer);
+        spawn_connection(
+            ServerTarget::Addr(peer),
+            settings.clone(),
+            from_outbound_tx.clone(),
+            to_outbound_tx.clone(),
+        );
+    }
+
+    let cipher = settings.cipher;
+
+    let mut seen = SeenMessages::new(identity.device_id.clone());
+    let mut clipboard = clipboard_options.monitor()?;
+    let (local_tx, mut local_rx) = mpsc::unbounded_channel();
+    let monitor_handle = clipboard.watch(local_tx.clone());
+
+    loop {
+        let message = tokio::select! {
+            Some(_) = local_rx.recv() => {
+                for (selection, content) in clipboard.changes() {
+                    let summary = content.summary();
+                    let Some(content) = clipboard_options.filters.filter(content) else {
+                        println!("Local {} changed: {}, filtered out", selection, summary);
+                        continue;
+                    };
+                    println!(
+                        "Local {} changed, sending to peers: {}",
+                        selection,
+                        content.summary()
+                    );
+                    record_history(
+                        &mut history,
+                        selection,
+                        &content,
+                        Some(&identity.device_id),
+                        Some(&identity.name),
+                    );
+                    let message = clipboard.stamp(
+                        ClipboardMessage::new(content, Some(identity.device_id.clone()))
+                            .with_device_name(&identity.name)
+                            .with_selection(selection),
+                    );
+                    forward_to_peers(message, cipher.as_ref(), &to_inbound_tx, &to_outbound_tx);
+                }
+                continue;
+            }
+            // Outbound connections decrypt in SyncClient, inbound ones are decrypted here
+            Some(mut message) = from_inbound_rx.recv() => {
+                if let Some(cipher) = &cipher {
+                    if let Err(e) = cipher.open(&mut message, None) {
+                        eprintln!("Rejected message from peer: {}", e);
+                        continue;
+                    }
+                }
+                message
+            }
+            Some(message) = from_outbound_rx.recv() => message,
+            else => break,
+        };
+
+        // Our own messages and copies arriving over a second path stop here
+        if !seen.insert(&message) {
+            continue;
+        }
+        // Concurrent copies: the newer one wins, the older one isn't passed on either
+        if !clipboard.accept(&message) {
+            println!(
+                "Ignored {} from peer {}, older than the current one: {}",
+                message.selection,
+                message.sender(),
+                message.summary()
+            );
+            continue;
+        }
+
+        println!(
+            "Received {} from peer {}: {}",
+            message.selection,
+            message.sender(),
+            message.summary()
+        );
+        // Filtered content is still passed on, other peers may want it
+        if !clipboard.syncs(message.selection) {
+            println!(
+                "Not syncing the {} here, only passing it on",
+                message.selection
+            );
+        } else if let Some(content) = clipboard_options.filters.filter(message.content.clone()) {
+            record_history(
+                &mut history,
+                message.selection,
+                &content,
+                message.client_id.as_deref(),
+                message.device_name.as_deref(),
+            );
+            if let Err(e) = clipboard.apply(message.selection, &content) {
+                eprintln!("Failed to set clipboard: {}", e);
+            }
+        } else {
+            println!("Filtered out, not applied to the local clipboard");
+        }
+        // Peers that aren't directly connected to the origin get it from us
+        forward_to_peers(message, cipher.as_ref(), &to_inbound_tx, &to_outbound_tx);
+    }
+
+    monitor_handle.abort();
+    Ok(())
+}
+
+fn forward_to_peers(
+    message: ClipboardMessage,
+    cipher: Option<&ContentCipher>,
+    to_inbound_tx: &broadcast::Sender<ClipboardMessage>,
+    to_outbound_tx: &broadcast::Sender<ClipboardMessage>,
+) {
+    // Sending fails when nobody is connected, which is fine
+    let _ = to_outbound_tx.send(message.clone());
+
+    let mut message = message;
+    if let Some(cipher) = cipher {
+        if let Err(e) = cipher.seal(&mut message, None) {
+            eprintln!("Failed to encrypt clipboard content: {}", e);
+            return;
+        }
+    }
+    let _ = to_inbound_tx.send(message);
+}
+
+fn run_history(action: HistoryAction, profile: &Profile) -> Result<()> {
+    let mut history = open_history(profile.limits.history())?;
+
+    match action {
+        HistoryAction::List { limit } => {
+            for entry in history.entries().rev().take(limit) {
+                print_entry(entry);
+            }
+        }
+        HistoryAction::Show { id, output } => {
+            let entry = history
+                .get(id)
+                .ok_or_else(|| anyhow::anyhow!("No history entry #{}", id))?;
+            println!("#{} {}", entry.id, entry.content.summary());
+            println!("Time:   {}", history::format_age(entry.timestamp));
+            println!("Origin: {}", entry.origin());
+            println!();
+            match &entry.content {
+                ClipboardContent::Text(text) => println!("{}", text),
+                ClipboardContent::Html { html, .. } => println!("{}", html),
+                ClipboardContent::Image { data, .. } => match output {
+                    Some(path) => {
+                        std::fs::write(&path, data)?;
+                        println!("Image saved to {}", path.display());
+                    }
+                    None => println!("Use --output <file.png> to save the image"),
+                },
+                ClipboardContent::Multi(_) => {
+                    let text = [TEXT_MIME, HTML_MIME]
+                        .iter()
+                        .find_map(|mime| entry.content.representation(mime));
+                    if let Some(text) = text {
+                        println!("{}", String::from_utf8_lossy(text));
+                    }
+                    if let Some(data) = entry.content.representation(PNG_MIME) {
+                        match output {
+                            Some(path) => {
+                                std::fs::write(&path, data)?;
+                                println!("Image saved to {}", path.display());
+                            }
+                            None => println!("Use --output <file.png> to save the image"),
+                        }
+                    }
+                }
+                // Never recorded
+                ClipboardContent::Encrypted { .. } | ClipboardContent::Files(_) => {}
+            }
+        }
+        HistoryAction::Search { query } => {
+            for entry in history.search(&query).rev() {
+                print_entry(entry);
+            }
+        }
+        HistoryAction::Restore { id, backend } => {
+            let entry = history
+                .get(id)
+                .ok_or_else(|| anyhow::anyhow!("No history entry #{}", id))?;
+            let mut clipboard =
+                ClipboardOptions::new(backend, false, None, None, profile).monitor()?;
+            clipboard.set_clipboard_content(&entry.content)?;
+            println!(
+                "Restored #{} to the clipboard: {}",
+                id,
+                entry.content.summary()
+            );
+        }
+        HistoryAction::Clear => {
+            history.clear()?;
+            println!("Clipboard history cleared");
+        }
+    }
+
+    Ok(())
+}
+
+fn print_entry(entry: &history::HistoryEntry) {
+    println!(
+        "#{:<5} {:>8}  {:<24} {}  {}",
+        entry.id,
+        history::format_age(entry.timestamp),
+        entry.origin(),
+        entry.content.summary(),
+        entry.preview(60)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::config::FilterKind;
+    use modules::protocol::{ContentKind, Hello};
+    use modules::sync::Representation;
+    use std::fmt;
+    use tokio::task::JoinHandle;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    // How long a clipboard has to keep its content to count as left alone
+    const QUIET: Duration = Duration::from_millis(300);
+    // Copied on the server to see that every client is connected
+    const READY: &str = "ready";
+
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn identity(name: &str) -> Identity {
+        Identity {
+            device_id: name.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    fn memory_options(memory: &MemoryBackend) -> ClipboardOptions {
+        ClipboardOptions {
+            backend: BackendKind::Memory,
+            memory: Some(memory.clone()),
+            poll_interval: Duration::from_millis(50),
+            image_limits: ImageLimits::default(),
+            primary: false,
+            clipboard_target: ClipboardTarget::Clipboard,
+            receive_dir: std::env::temp_dir()
+                .join(format!("copi-test-{:x}", rand::random::<u64>())),
+            max_files_size: files::MAX_FILES_SIZE,
+            filters: Filters::default(),
+        }
+    }
+
+    /// A `copi server` and its `copi client`s on in-memory clipboards, stopped when dropped
+    struct Network {
+        addr: SocketAddr,
+        passphrase: Option<&'static str>,
+        server: MemoryBackend,
+        clients: Vec<MemoryBackend>,
+        tasks: Vec<JoinHandle<Result<()>>>,
+    }
+
+    impl Network {
+        fn start(passphrase: Option<&'static str>) -> Self {
+            let addr = free_addr();
+            let server = MemoryBackend::new();
+            let options = ServerOptions {
+                addr,
+                websocket: None,
+                relay_only: false,
+                clipboard: memory_options(&server),
+                room_tokens: Vec::new(),
+                replay: true,
+                group: None,
+                heartbeat: None,
+                hello_timeout: None,
+                identity: identity("server"),
+            };
+            let tls = ServerTlsOptions {
+                enabled: false,
+                cert: None,
+                key: None,
+            };
+            let cipher = passphrase.map(|p| ContentCipher::from_passphrase(p).unwrap());
+            let task = tokio::spawn(run_server(options, tls, cipher, None, None));
+            Self {
+                addr,
+                passphrase,
+                server,
+                clients: Vec::new(),
+                tasks: vec![task],
+            }
+        }
+
+        // A client with default options
+        fn client(&mut self, name: &str) -> MemoryBackend {
+            let memory = MemoryBackend::new();
+            self.join(name, memory_options(&memory));
+            memory
+        }
+
+        // A client on the in-memory clipboard of `options`
+        fn join(&mut self, name: &str, options: ClipboardOptions) {
+            let tls = ClientTlsOptions {
+                enabled: false,
+                ca: None,
+                fingerprint: None,
+                server_name: None,
+            };
+            let cipher = self
+                .passphrase
+                .map(|p| ContentCipher::from_passphrase(p).unwrap());
+            let settings = ConnectionSettings::new(
+                tls,
+                cipher,
+                None,
+                None,
+                identity(name),
+                Duration::from_millis(50),
+            )
+            .unwrap();
+            self.clients.push(options.memory.clone().unwrap());
+            self.tasks.push(tokio::spawn(run_client(
+                ServerTarget::Addr(self.addr),
+                settings,
+                options,
+                None,
+            )));
+        }
+
+        /// Wait until every client is connected, which leaves READY on all clipboards
+        async fn ready(&self) {
+            self.server
+                .set_content(ClipboardContent::Text(READY.to_string()));
+            for client in &self.clients {
+                wait_for(client, READY).await;
+            }
+        }
+    }
+
+    impl Drop for Network {
+        fn drop(&mut self) {
+            self.tasks.iter().for_each(JoinHandle::abort);
+        }
+    }
+
+    fn as_text(content: Option<ClipboardContent>) -> Option<String> {
+        match content {
+            Some(ClipboardContent::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn text(memory: &MemoryBackend) -> Option<String> {
+        as_text(memory.content())
+    }
+
+    fn primary_text(memory: &MemoryBackend) -> Option<String> {
+        as_text(memory.primary())
+    }
+
+    // Poll `read` until `done` accepts what it returns, which is returned
+    async fn eventually<T: fmt::Debug>(read: impl Fn() -> T, done: impl Fn(&T) -> bool) -> T {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        loop {
+            let value = read();
+            if done(&value) {
+                return value;
+            }
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "gave up waiting, found {:?}",
+                value
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    // Poll `read` for a while, it must return `expected` every time
+    async fn stays<T: PartialEq + fmt::Debug>(read: impl Fn() -> T, expected: T) {
+        let deadline = tokio::time::Instant::now() + QUIET;
+        while tokio::time::Instant::now() < deadline {
+            assert_eq!(read(), expected);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    async fn wait_for(memory: &MemoryBackend, expected: &str) {
+        eventually(|| text(memory), |text| text.as_deref() == Some(expected)).await;
+    }
+
+    async fn wait_for_primary(memory: &MemoryBackend, expected: &str) {
+        eventually(
+            || primary_text(memory),
+            |text| text.as_deref() == Some(expected),
+        )
+        .await;
+    }
+
+    // Server plus two clients, every side copies once and the other two must follow
+    async fn three_way_sync(passphrase: Option<&'static str>) {
+        let mut network = Network::start(passphrase);
+        let alice = network.client("alice");
+        let bob = network.client("bob");
+        network.ready().await;
+        let server = &network.server;
+
+        alice.set_content(ClipboardContent::Text("from alice".to_string()));
+        wait_for(server, "from alice").await;
+        wait_for(&bob, "from alice").await;
+
+        bob.set_content(ClipboardContent::Text("from bob".to_string()));
+        wait_for(server, "from bob").await;
+        wait_for(&alice, "from bob").await;
+
+        server.set_content(ClipboardContent::Text("from server".to_string()));
+        wait_for(&alice, "from server").await;
+        wait_for(&bob, "from server").await;
+
+        // Nothing is echoed back to overwrite a later copy
+        let from_server = Some("from server".to_string());
+        tokio::join!(
+            stays(|| text(server), from_server.clone()),
+            stays(|| text(&alice), from_server.clone()),
+            stays(|| text(&bob), from_server.clone()),
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_copies_converge() {
+        let mut network = Network::start(None);
+        let alice = network.client("alice");
+        let bob = network.client("bob");
+        network.ready().await;
+        let server = &network.server;
+
+        // Both copies are in flight at the same time, each side sees the other's later
+        alice.set_content(ClipboardContent::Text("from alice".to_string()));
+        bob.set_content(ClipboardContent::Text("from bob".to_string()));
+
+        let texts = || [text(server), text(&alice), text(&bob)];
+        let [winner, ..] = eventually(texts, |texts| {
+            texts[0].as_deref() != Some(READY) && texts.iter().all(|text| *text == texts[0])
+        })
+        .await;
+        assert!(matches!(
+            winner.as_deref(),
+            Some("from alice") | Some("from bob")
+        ));
+        // And they stay that way instead of swapping
+        stays(texts, [winner.clone(), winner.clone(), winner]).await;
+    }
+
+    #[tokio::test]
+    async fn primary_selection_syncs_separately() {
+        let mut network = Network::start(None);
+        let alice = MemoryBackend::new();
+        let bob = MemoryBackend::new();
+        network.join(
+            "alice",
+            ClipboardOptions {
+                primary: true,
+                ..memory_options(&alice)
+            },
+        );
+        network.join(
+            "bob",
+            ClipboardOptions {
+                primary: true,
+                clipboard_target: ClipboardTarget::Both,
+                ..memory_options(&bob)
+            },
+        );
+        let carol = network.client("carol");
+        network.ready().await;
+        let server = &network.server;
+
+        // Selecting text reaches the PRIMARY of clients that sync it and nothing else
+        alice.set_primary(ClipboardContent::Text("selected".to_string()));
+        wait_for_primary(&bob, "selected").await;
+        let ready = Some(READY.to_string());
+        tokio::join!(
+            stays(|| text(&bob), ready.clone()),
+            stays(|| text(server), ready.clone()),
+            stays(|| primary_text(server), None),
+            stays(|| text(&carol), ready.clone()),
+        );
+
+        // A copy goes to the clipboard, and to PRIMARY too where it is mapped there
+        alice.set_content(ClipboardContent::Text("copied".to_string()));
+        wait_for(server, "copied").await;
+        wait_for(&carol, "copied").await;
+        wait_for(&bob, "copied").await;
+        wait_for_primary(&bob, "copied").await;
+        assert_eq!(primary_text(&alice).as_deref(), Some("selected"));
+    }
+
+    fn tiny_png() -> Vec<u8> {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgba8(2, 2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    fn mimes(content: Option<ClipboardContent>) -> Vec<String> {
+        match content {
+            Some(ClipboardContent::Multi(formats)) => {
+                formats.into_iter().map(|format| format.mime).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    async fn wait_for_formats(memory: &MemoryBackend, expected: &[&str]) {
+        eventually(|| mimes(memory.content()), |mimes| mimes == expected).await;
+    }
+
+    #[test]
+    fn single_format_copies_stay_plain() {
+        let text = Representation::new(TEXT_MIME, b"hello".to_vec());
+        let html = Representation::new(HTML_MIME, b"<b>hello</b>".to_vec());
+        let png = Representation::new(PNG_MIME, tiny_png());
+
+        let plain = ClipboardContent::from_representations(vec![text.clone()]);
+        assert_eq!(as_text(plain).as_deref(), Some("hello"));
+        let rich = ClipboardContent::from_representations(vec![text.clone(), html.clone()]);
+        assert!(matches!(
+            rich,
+            Some(ClipboardContent::Html { html, text }) if html == "<b>hello</b>" && text == "hello"
+        ));
+
+        // Peers without multi-format support get the richest format they understand
+        let item = ClipboardContent::from_representations(vec![text, html, png]).unwrap();
+        assert!(matches!(
+            item.downgrade_for(&Hello::legacy().content_kinds),
+            Some(ClipboardContent::Image {
+                width: 2,
+                height: 2,
+                ..
+            })
+        ));
+        let text_only = item.downgrade_for(&[ContentKind::Text]);
+        assert_eq!(as_text(text_only).as_deref(), Some("hello"));
+    }
+
+    #[tokio::test]
+    async fn multi_format_copies_keep_every_format() {
+        let mut network = Network::start(None);
+        let alice = network.client("alice");
+        let bob = network.client("bob");
+        let carol = MemoryBackend::new();
+        network.join(
+            "carol",
+            ClipboardOptions {
+                filters: Filters {
+                    kinds: Some(vec![FilterKind::Text, FilterKind::Html]),
+                    ignore: None,
+                },
+                ..memory_options(&carol)
+            },
+        );
+        network.ready().await;
+
+        // A browser copy: markup, its text, the picture and some metadata
+        let copy = ClipboardContent::from_representations(vec![
+            Representation::new(HTML_MIME, b"<img alt=\"logo\">".to_vec()),
+            Representation::new(TEXT_MIME, b"logo".to_vec()),
+            Representation::new(PNG_MIME, tiny_png()),
+            Representation::new("chromium/x-source-url", b"https://example.com".to_vec()),
+        ])
+        .unwrap();
+        alice.set_content(copy.clone());
+
+        let all = ["chromium/x-source-url", PNG_MIME, HTML_MIME, TEXT_MIME];
+        wait_for_formats(&bob, &all).await;
+        wait_for_formats(&network.server, &all).await;
+        let received = bob.content().unwrap();
+        for mime in all {
+            assert_eq!(received.representation(mime), copy.representation(mime));
+        }
+        // Filtered formats are dropped, the rest of the copy still arrives
+        wait_for_formats(&carol, &["chromium/x-source-url", HTML_MIME, TEXT_MIME]).await;
+    }
+
+    #[tokio::test]
+    async fn copied_files_are_transferred() {
+        let mut network = Network::start(None);
+        let alice = network.client("alice");
+        let bob = MemoryBackend::new();
+        let bob_options = memory_options(&bob);
+        let receive_dir = bob_options.receive_dir.clone();
+        network.join("bob", bob_options);
+        network.ready().await;
+
+        let source = std::env::temp_dir().join(format!("copi-test-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&source).unwrap();
+        let report = source.join("report.txt");
+        let photo = source.join("photo.bin");
+        std::fs::write(&report, "quarterly numbers").unwrap();
+        std::fs::write(&photo, [0u8, 1, 2, 255]).unwrap();
+        alice.set_files(vec![report, photo]);
+
+        // Bob's clipboard points at local copies in his receive directory
+        let files = eventually(|| bob.files(), |files| files.len() == 2).await;
+        assert!(files.iter().all(|path| path.starts_with(&receive_dir)));
+        assert_eq!(files[0].file_name().unwrap(), "report.txt");
+        assert_eq!(std::fs::read(&files[0]).unwrap(), b"quarterly numbers");
+        assert_eq!(std::fs::read(&files[1]).unwrap(), [0u8, 1, 2, 255]);
+        // Nothing is sent back, so alice keeps her own files
+        stays(|| alice.files()[0].starts_with(&source), true).await;
+
+        let _ = std::fs::remove_dir_all(&source);
+        let _ = std::fs::remove_dir_all(&receive_dir);
+    }
+
+    #[tokio::test]
+    async fn normal_mode_forwards_between_clients() {
+        three_way_sync(None).await;
+    }
+
+    #[tokio::test]
+    async fn normal_mode_forwards_encrypted_content() {
+        three_way_sync(Some("correct horse battery staple")).await;
+    }
+
+    #[test]
+    fn command_line_flags_override_the_profile() {
+        let profile = Profile {
+            backend: Some(BackendKind::Memory),
+            clipboard_target: Some(ClipboardTarget::Primary),
+            receive_dir: Some(PathBuf::from("/from/config")),
+            ..Profile::default()
+        };
+        let options = ClipboardOptions::new(None, false, None, None, &profile);
+        assert!(matches!(options.backend, BackendKind::Memory));
+        assert!(matches!(options.clipboard_target, ClipboardTarget::Primary));
+        assert_eq!(options.receive_dir, PathBuf::from("/from/config"));
+
+        let options = ClipboardOptions::new(
+            Some(BackendKind::Auto),
+            false,
+            Some(ClipboardTarget::Clipboard),
+            Some(PathBuf::from("/from/flag")),
+            &profile,
+        );
+        assert!(matches!(options.backend, BackendKind::Auto));
+        assert!(matches!(
+            options.clipboard_target,
+            ClipboardTarget::Clipboard
+        ));
+        assert_eq!(options.receive_dir, PathBuf::from("/from/flag"));
+    }
+
+    #[test]
+    fn room_tokens_from_the_command_line_override_the_profile() {
+        let profile = HashMap::from([
+            ("team".to_string(), "from config".to_string()),
+            ("family".to_string(), "kept".to_string()),
+        ]);
+        let tokens = build_room_tokens(vec!["team=from flag".to_string()], &profile).unwrap();
+        let token = |room: &str| {
+            let (_, token) = tokens.iter().find(|(name, _)| name == room).unwrap();
+            token.respond("nonce")
+        };
+        let expected = |token: &str| AuthToken::new(token).unwrap().respond("nonce");
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(token("team"), expected("from flag"));
+        assert_eq!(token("family"), expected("kept"));
+
+        assert!(build_room_tokens(vec!["no separator".to_string()], &profile).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::modules::sync::FileEntry;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 复制的文件总大小限制：100MB（超过单帧大小的内容会分块传输）
+pub const MAX_FILES_SIZE: usize = 100 * 1024 * 1024;
+// 接收目录中保留最近几次接收的文件，更早的自动删除
+const KEEP_RECEIVED: usize = 10;
+
+/// Where received files are stored by default: `~/Downloads/copi`
+pub fn default_receive_dir() -> PathBuf {
+    dirs::download_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("copi")
+}
+
+/// Cheap fingerprint of a file list (paths, sizes and modification times), so the
+/// files are only read again when something changed
+pub fn files_key(paths: &[PathBuf]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"files:");
+    for path in paths {
+        hasher.update(path.to_string_lossy().as_bytes());
+        if let Ok(metadata) = path.metadata() {
+            hasher.update(metadata.len().to_le_bytes());
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            hasher.update(modified.as_nanos().to_le_bytes());
+        }
+        hasher.update(b"\0");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Read copied files for sending. Directories are skipped, and the whole list is refused
+/// when the files are larger than `max_size` together.
+pub fn load(paths: &[PathBuf], max_size: usize) -> Result<Vec<FileEntry>> {
+    let mut files = Vec::new();
+    let mut total = 0;
+    for path in paths {
+        let metadata = path
+            .metadata()
+            .with_context(|| format!("Cannot read {}", path.display()))?;
+        if !metadata.is_file() {
+            println!("Skipping {}: only files are synced", path.display());
+            continue;
+        }
+        total += metadata.len() as usize;
+        if total > max_size {
+            anyhow::bail!(
+                "Copied files are larger than {} MB, not syncing them",
+                max_size / 1024 / 1024
+            );
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "file".to_string());
+        let data =
+            std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        files.push(FileEntry { name, data });
+    }
+    if files.is_empty() {
+        anyhow::bail!("No files to sync");
+    }
+    Ok(files)
+}
+
+/// Store received files in a new directory under `dir`, returning their paths.
+///
+/// Files larger than `max_size` together are refused before anything is written, and
+/// only the latest few received directories are kept.
+pub fn save(files: &[FileEntry], dir: &Path, max_size: usize) -> Result<Vec<PathBuf>> {
+    let total: usize = files.iter().map(|file| file.data.len()).sum();
+    if total > max_size {
+        anyhow::bail!(
+            "Received files are larger than {} MB, not saving them",
+            max_size / 1024 / 1024
+        );
+    }
+
+    // 每次接收使用单独的目录，同名文件不会互相覆盖
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    // Past the attempts already there, earlier ones may have been pruned
+    let mut attempt = received(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|((other, _), _)| *other == millis)
+        .map(|((_, attempt), _)| attempt + 1)
+        .max()
+        .unwrap_or(1);
+    let name = |attempt| match attempt {
+        1 => millis.to_string(),
+        _ => format!("{}-{}", millis, attempt),
+    };
+    while dir.join(name(attempt)).exists() {
+        attempt += 1;
+    }
+    let target = dir.join(name(attempt));
+    std::fs::create_dir_all(&target)
+        .with_context(|| format!("Cannot create {}", target.display()))?;
+
+    let mut paths = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        // Only the last component counts, a peer can't write outside the directory
+        let name = Path::new(&file.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "file".to_string());
+        let mut path = target.join(&name);
+        if path.exists() {
+            path = target.join(format!("{}-{}", index + 1, name));
+        }
+        std::fs::write(&path, &file.data)
+            .with_context(|| format!("Cannot write {}", path.display()))?;
+        paths.push(path);
+    }
+    println!("Saved {} file(s) to {}", paths.len(), target.display());
+    if let Err(e) = prune(dir) {
+        eprintln!("Failed to remove old received files: {}", e);
+    }
+    Ok(paths)
+}
+
+// Remove all but the newest KEEP_RECEIVED directories created by save(); anything
+// else in `dir` is left alone
+fn prune(dir: &Path) -> Result<()> {
+    let mut received = received(dir)?;
+    received.sort();
+    let old = received.len().saturating_sub(KEEP_RECEIVED);
+    for (_, path) in received.drain(..old) {
+        std::fs::remove_dir_all(&path)
+            .with_context(|| format!("Cannot remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+// Directories created by save(), named `millis` or `millis-attempt`
+fn received(dir: &Path) -> Result<Vec<((u128, u32), PathBuf)>> {
+    let mut received = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let (millis, attempt) = name.split_once('-').unwrap_or((&name, "1"));
+        if let (Ok(millis), Ok(attempt)) = (millis.parse::<u128>(), attempt.parse::<u32>()) {
+            if entry.file_type()?.is_dir() {
+                received.push(((millis, attempt), entry.path()));
+            }
+        }
+    }
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("copi-test-{:x}", rand::random::<u64>()))
+    }
+
+    fn file(name: &str, size: usize) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            data: vec![b'x'; size],
+        }
+    }
+
+    #[test]
+    fn oversized_files_are_not_saved() {
+        let dir = temp_dir();
+        let files = [file("a.txt", 600), file("b.txt", 600)];
+        assert!(save(&files, &dir, 1000).is_err());
+        assert!(!dir.exists());
+
+        let paths = save(&files, &dir, 1200).unwrap();
+        assert_eq!(paths.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_cannot_leave_the_receive_directory() {
+        let dir = temp_dir();
+        let paths = save(&[file("../../escape.txt", 1)], &dir, 1000).unwrap();
+        assert!(paths[0].starts_with(&dir));
+        assert!(paths[0].ends_with("escape.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_latest_received_files_are_kept() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(dir.join("mine")).unwrap();
+        let saved: Vec<PathBuf> = (0..KEEP_RECEIVED + 3)
+            .map(|_| save(&[file("a.txt", 1)], &dir, 1000).unwrap().remove(0))
+            .collect();
+
+        let (removed, kept) = saved.split_at(3);
+        assert!(removed.iter().all(|path| !path.exists()));
+        assert!(kept.iter().all(|path| path.exists()));
+        // Not created by us, not ours to remove
+        assert!(dir.join("mine").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
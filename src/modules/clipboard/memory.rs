@@ -0,0 +1,129 @@
+use super::ClipboardBackend;
+use crate::modules::protocol::ContentKind;
+use crate::modules::sync::ClipboardContent;
+use crate::modules::watcher::ChangeSender;
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+#[derive(Default)]
+struct MemoryState {
+    content: Option<ClipboardContent>,
+    // Copied files replace the content and the other way round
+    files: Vec<PathBuf>,
+    primary: Option<ClipboardContent>,
+    watchers: Vec<ChangeSender>,
+}
+
+/// Clipboard that only lives in memory.
+///
+/// Clones share the same content, so a test can keep one handle to simulate the
+/// user copying (`set_content`) and inspect what sync wrote (`content`) while the
+/// monitor owns another.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn content(&self) -> Option<ClipboardContent> {
+        self.state.lock().unwrap().content.clone()
+    }
+
+    /// Replace the content and notify watchers, like a local copy would
+    pub fn set_content(&self, content: ClipboardContent) {
+        let mut state = self.state.lock().unwrap();
+        state.content = Some(content);
+        state.files.clear();
+        state.watchers.retain(|tx| tx.send(()).is_ok());
+    }
+
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.state.lock().unwrap().files.clone()
+    }
+
+    /// Put files on the clipboard and notify watchers, like copying them in a file manager
+    pub fn set_files(&self, paths: Vec<PathBuf>) {
+        let mut state = self.state.lock().unwrap();
+        state.files = paths;
+        state.content = None;
+        state.watchers.retain(|tx| tx.send(()).is_ok());
+    }
+
+    pub fn primary(&self) -> Option<ClipboardContent> {
+        self.state.lock().unwrap().primary.clone()
+    }
+
+    /// Replace the PRIMARY selection and notify watchers, like selecting text would
+    pub fn set_primary(&self, content: ClipboardContent) {
+        let mut state = self.state.lock().unwrap();
+        state.primary = Some(content);
+        state.watchers.retain(|tx| tx.send(()).is_ok());
+    }
+}
+
+impl ClipboardBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn supported_kinds(&self) -> Vec<ContentKind> {
+        vec![
+            ContentKind::Text,
+            ContentKind::Image,
+            ContentKind::Html,
+            ContentKind::Files,
+            ContentKind::Multi,
+        ]
+    }
+
+    fn read(&mut self) -> Result<ClipboardContent> {
+        self.content()
+            .ok_or_else(|| anyhow::anyhow!("Clipboard is empty"))
+    }
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<()> {
+        self.set_content(content.clone());
+        Ok(())
+    }
+
+    fn watch(&self, tx: ChangeSender, _poll_interval: Duration) -> JoinHandle<()> {
+        self.state.lock().unwrap().watchers.push(tx);
+        // Notifications come from set_content, nothing to run
+        tokio::spawn(async {})
+    }
+
+    fn read_files(&mut self) -> Result<Vec<PathBuf>> {
+        Ok(self.files())
+    }
+
+    fn write_files(&mut self, paths: &[PathBuf]) -> Result<()> {
+        self.set_files(paths.to_vec());
+        Ok(())
+    }
+
+    fn supports_primary(&self) -> bool {
+        true
+    }
+
+    fn read_primary(&mut self) -> Result<ClipboardContent> {
+        self.primary()
+            .ok_or_else(|| anyhow::anyhow!("Nothing selected"))
+    }
+
+    fn write_primary(&mut self, content: &ClipboardContent) -> Result<()> {
+        self.set_primary(content.clone());
+        Ok(())
+    }
+
+    // Watchers registered by `watch` hear about both selections
+    fn watch_primary(&self, _tx: ChangeSender, _poll_interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async {})
+    }
+}
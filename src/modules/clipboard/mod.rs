@@ -0,0 +1,652 @@
+use crate::modules::clock::{self, HybridClock, Version};
+use crate::modules::protocol::ContentKind;
+use crate::modules::sync::{ClipboardContent, ClipboardMessage, Selection};
+use crate::modules::watcher::{self, ChangeSender, WatchHandle};
+use anyhow::Result;
+use arboard::ImageData;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+pub mod files;
+mod memory;
+mod native;
+#[cfg(target_os = "linux")]
+mod wayland;
+
+pub use memory::MemoryBackend;
+pub use native::ArboardBackend;
+#[cfg(target_os = "linux")]
+pub use wayland::WlClipboardBackend;
+
+// 图片大小限制：64MB（超过单帧大小的内容会分块传输）
+const MAX_IMAGE_SIZE: usize = 64 * 1024 * 1024;
+// 图片尺寸限制：16384x16384
+const MAX_IMAGE_DIMENSION: u32 = 16384;
+
+/// Images read from the clipboard are scaled down until they fit these limits
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_size: usize,
+    pub max_dimension: u32,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_size: MAX_IMAGE_SIZE,
+            max_dimension: MAX_IMAGE_DIMENSION,
+        }
+    }
+}
+
+/// A system (or fake) clipboard that `ClipboardMonitor` reads from and writes to.
+pub trait ClipboardBackend: Send {
+    fn name(&self) -> &'static str;
+
+    /// Content kinds `write` can apply, anything else is downgraded first
+    fn supported_kinds(&self) -> Vec<ContentKind>;
+
+    fn read(&mut self) -> Result<ClipboardContent>;
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<()>;
+
+    /// Backends that convert images themselves should respect these limits
+    fn set_image_limits(&mut self, _limits: ImageLimits) {}
+
+    /// Notify `tx` whenever the clipboard may have changed, polling every
+    /// `poll_interval` when there is no better way
+    fn watch(&self, tx: ChangeSender, poll_interval: Duration) -> JoinHandle<()> {
+        watcher::spawn_poller(tx, poll_interval)
+    }
+
+    /// Paths of files copied in a file manager, empty when the clipboard holds
+    /// anything else
+    fn read_files(&mut self) -> Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Put files on the clipboard so they can be pasted in a file manager
+    fn write_files(&mut self, _paths: &[PathBuf]) -> Result<()> {
+        anyhow::bail!("{} backend cannot copy files", self.name())
+    }
+
+    /// Whether the backend has a PRIMARY selection (X11 and Wayland)
+    fn supports_primary(&self) -> bool {
+        false
+    }
+
+    /// Text in the PRIMARY selection
+    fn read_primary(&mut self) -> Result<ClipboardContent> {
+        anyhow::bail!("{} backend has no primary selection", self.name())
+    }
+
+    /// Replace the PRIMARY selection, only called with text
+    fn write_primary(&mut self, _content: &ClipboardContent) -> Result<()> {
+        anyhow::bail!("{} backend has no primary selection", self.name())
+    }
+
+    /// Like `watch`, for the PRIMARY selection
+    fn watch_primary(&self, tx: ChangeSender, poll_interval: Duration) -> JoinHandle<()> {
+        watcher::spawn_poller(tx, poll_interval)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// wl-clipboard on Wayland when installed, arboard otherwise
+    Auto,
+    Arboard,
+    #[cfg(target_os = "linux")]
+    WlClipboard,
+    /// In-memory clipboard, for headless machines and tests
+    Memory,
+}
+
+/// Local selection(s) received clipboard content is written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipboardTarget {
+    #[default]
+    Clipboard,
+    /// Middle click pastes what was copied on other devices, Ctrl+V keeps the local copy
+    Primary,
+    Both,
+}
+
+pub struct ClipboardMonitor {
+    backend: Box<dyn ClipboardBackend>,
+    last_hash: Option<String>,
+    poll_interval: Duration,
+    // Watch and sync PRIMARY as a channel of its own
+    primary: bool,
+    primary_hash: Option<String>,
+    clipboard_target: ClipboardTarget,
+    // Received files are saved here before they are put on the clipboard
+    receive_dir: PathBuf,
+    max_files_size: usize,
+    clock: HybridClock,
+    // Newest change seen so far per selection, received or local
+    current: HashMap<Selection, Version>,
+}
+
+impl ClipboardMonitor {
+    pub fn from_kind(kind: BackendKind) -> Result<Self> {
+        let backend: Box<dyn ClipboardBackend> = match kind {
+            BackendKind::Auto => Self::detect_backend()?,
+            BackendKind::Arboard => Box::new(ArboardBackend::new()?),
+            #[cfg(target_os = "linux")]
+            BackendKind::WlClipboard => Box::new(WlClipboardBackend::default()),
+            BackendKind::Memory => Box::new(MemoryBackend::new()),
+        };
+        Ok(Self::with_backend(backend))
+    }
+
+    pub fn with_backend(backend: Box<dyn ClipboardBackend>) -> Self {
+        println!("Using {} clipboard backend", backend.name());
+        Self {
+            backend,
+            last_hash: None,
+            poll_interval: watcher::DEFAULT_POLL_INTERVAL,
+            primary: false,
+            primary_hash: None,
+            clipboard_target: ClipboardTarget::Clipboard,
+            receive_dir: files::default_receive_dir(),
+            max_files_size: files::MAX_FILES_SIZE,
+            clock: HybridClock::new(),
+            current: HashMap::new(),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn with_image_limits(mut self, limits: ImageLimits) -> Self {
+        self.backend.set_image_limits(limits);
+        self
+    }
+
+    /// Directory received files are saved in
+    pub fn with_receive_dir(mut self, receive_dir: PathBuf) -> Self {
+        self.receive_dir = receive_dir;
+        self
+    }
+
+    /// Copied or received files larger than this together are not synced
+    pub fn with_max_files_size(mut self, max_files_size: usize) -> Self {
+        self.max_files_size = max_files_size;
+        self
+    }
+
+    /// Also watch the PRIMARY selection and sync it separately from the clipboard
+    pub fn with_primary(mut self, primary: bool) -> Self {
+        if primary && !self.backend.supports_primary() {
+            println!(
+                "{} backend has no primary selection, only syncing the clipboard",
+                self.backend.name()
+            );
+            return self;
+        }
+        self.primary = primary;
+        self
+    }
+
+    /// Write received clipboard content to the PRIMARY selection instead or as well
+    pub fn with_clipboard_target(mut self, target: ClipboardTarget) -> Self {
+        if target != ClipboardTarget::Clipboard && !self.backend.supports_primary() {
+            println!(
+                "{} backend has no primary selection, writing to the clipboard",
+                self.backend.name()
+            );
+            return self;
+        }
+        self.clipboard_target = target;
+        self
+    }
+
+    fn detect_backend() -> Result<Box<dyn ClipboardBackend>> {
+        // Try to detect if we're running on Wayland
+        #[cfg(target_os = "linux")]
+        {
+            let is_wayland = std::env::var("WAYLAND_DISPLAY").is_ok();
+
+            if is_wayland {
+                // Check if wl-clipboard tools are available
+                if WlClipboardBackend::is_available() {
+                    println!("Detected Wayland, using wl-clipboard backend");
+                    return Ok(Box::new(WlClipboardBackend::default()));
+                } else {
+                    println!(
+                        "Wayland detected but wl-clipboard not found, falling back to arboard"
+                    );
+                    println!("Install wl-clipboard for better Wayland support:");
+                    println!("  Ubuntu/Debian: sudo apt install wl-clipboard");
+                    println!("  Fedora: sudo dnf install wl-clipboard");
+                    println!("  Arch: sudo pacman -S wl-clipboard");
+                }
+            }
+        }
+
+        // Use arboard as default or fallback
+        Ok(Box::new(ArboardBackend::new()?))
+    }
+
+    /// Notify `tx` whenever the clipboard may have changed.
+    ///
+    /// Uses change events of the backend where possible and falls back to polling.
+    pub fn watch(&self, tx: ChangeSender) -> WatchHandle {
+        // Pick up whatever is already on the clipboard at startup
+        let _ = tx.send(());
+
+        let mut handles = vec![self.backend.watch(tx.clone(), self.poll_interval)];
+        if self.primary {
+            handles.push(self.backend.watch_primary(tx, self.poll_interval));
+        }
+        WatchHandle(handles)
+    }
+
+    /// Whether messages for `selection` are applied here
+    pub fn syncs(&self, selection: Selection) -> bool {
+        selection == Selection::Clipboard || self.primary
+    }
+
+    /// Stamp the message about a local change, which becomes the newest change
+    pub fn stamp(&mut self, mut message: ClipboardMessage) -> ClipboardMessage {
+        message.clock = Some(self.clock.tick());
+        if let Some(version) = message.version() {
+            self.current.insert(message.selection, version);
+        }
+        message
+    }
+
+    /// Last writer wins: whether a received message is newer than every change of its
+    /// selection seen so far, in which case it becomes the newest one.
+    ///
+    /// Messages from versions without a clock can't be ordered and are always accepted.
+    pub fn accept(&mut self, message: &ClipboardMessage) -> bool {
+        let Some(version) = message.version() else {
+            return true;
+        };
+        if !clock::plausible(version.clock) {
+            eprintln!(
+                "Ignoring {} from {}: its clock is too far ahead of ours",
+                message.content.summary(),
+                message.sender()
+            );
+            return false;
+        }
+        self.clock.observe(version.clock);
+        if self
+            .current
+            .get(&message.selection)
+            .is_some_and(|current| version <= *current)
+        {
+            return false;
+        }
+        self.current.insert(message.selection, version);
+        true
+    }
+
+    fn hash_content(content: &ClipboardContent) -> String {
+        let mut hasher = Sha256::new();
+        match content {
+            ClipboardContent::Text(text) => {
+                hasher.update(b"text:");
+                hasher.update(text.as_bytes());
+            }
+            ClipboardContent::Image {
+                data,
+                width,
+                height,
+            } => {
+                hasher.update(b"image:");
+                hasher.update(data);
+                hasher.update(width.to_le_bytes());
+                hasher.update(height.to_le_bytes());
+            }
+            ClipboardContent::Html { html, text } => {
+                hasher.update(b"html:");
+                hasher.update(html.as_bytes());
+                hasher.update(text.as_bytes());
+            }
+            ClipboardContent::Encrypted { nonce, data } => {
+                hasher.update(b"encrypted:");
+                hasher.update(nonce);
+                hasher.update(data);
+            }
+            ClipboardContent::Files(files) => {
+                hasher.update(b"files:");
+                for file in files {
+                    hasher.update(file.name.as_bytes());
+                    hasher.update(b"\0");
+                    hasher.update(&file.data);
+                }
+            }
+            ClipboardContent::Multi(formats) => {
+                hasher.update(b"multi:");
+                for format in formats {
+                    hasher.update(format.mime.as_bytes());
+                    hasher.update(b"\0");
+                    hasher.update((format.data.len() as u64).to_le_bytes());
+                    hasher.update(&format.data);
+                }
+            }
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    // Remember the hash of `content`, returns false if it was already the last one
+    fn remember(last_hash: &mut Option<String>, content: &ClipboardContent) -> bool {
+        let hash = Self::hash_content(content);
+        if last_hash.as_ref() == Some(&hash) {
+            return false;
+        }
+        *last_hash = Some(hash);
+        true
+    }
+
+    /// Local changes since the last call, of the clipboard and PRIMARY when it is synced
+    pub fn changes(&mut self) -> Vec<(Selection, ClipboardContent)> {
+        let mut changes = Vec::new();
+        if let Ok(Some(content)) = self.get_clipboard_content() {
+            changes.push((Selection::Clipboard, content));
+        }
+        if self.primary {
+            // Nothing selected is the usual case, not an error worth logging
+            if let Ok(content) = self.backend.read_primary() {
+                if Self::remember(&mut self.primary_hash, &content) {
+                    changes.push((Selection::Primary, content));
+                }
+            }
+        }
+        changes
+    }
+
+    pub fn get_clipboard_content(&mut self) -> Result<Option<ClipboardContent>> {
+        // 文件管理器复制的文件优先，否则只能读到没有意义的路径文本
+        let paths = self.backend.read_files().unwrap_or_default();
+        if !paths.is_empty() {
+            return Ok(self.read_files(&paths));
+        }
+
+        match self.backend.read() {
+            Ok(content) => Ok(Self::remember(&mut self.last_hash, &content).then_some(content)),
+            Err(e) => {
+                // 记录错误但不中断程序
+                eprintln!("Error reading clipboard: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    // Files are only read when the list or the files themselves changed
+    fn read_files(&mut self, paths: &[PathBuf]) -> Option<ClipboardContent> {
+        let key = files::files_key(paths);
+        if self.last_hash.as_ref() == Some(&key) {
+            return None;
+        }
+        self.last_hash = Some(key);
+        match files::load(paths, self.max_files_size) {
+            Ok(files) => Some(ClipboardContent::Files(files)),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        }
+    }
+
+    pub fn set_clipboard_content(&mut self, content: &ClipboardContent) -> Result<()> {
+        // 后端不支持的类型先降级（例如 HTML 转为纯文本）
+        let supported = self.backend.supported_kinds();
+        let applied = content.downgrade_for(&supported).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} backend cannot apply {}",
+                self.backend.name(),
+                content.summary()
+            )
+        })?;
+        if let ClipboardContent::Files(files) = &applied {
+            // 先保存到接收目录，剪贴板上放的是本地文件路径
+            let paths = files::save(files, &self.receive_dir, self.max_files_size)?;
+            self.backend.write_files(&paths)?;
+            self.last_hash = Some(files::files_key(&paths));
+            return Ok(());
+        }
+        self.backend.write(&applied)?;
+
+        // 记录实际写入内容的 hash，避免读回时被当作新的本地变化
+        self.last_hash = Some(Self::hash_content(&applied));
+        Ok(())
+    }
+
+    fn set_primary_content(&mut self, content: &ClipboardContent) -> Result<()> {
+        // PRIMARY 只保存文本
+        let applied = content
+            .downgrade_for(&[ContentKind::Text])
+            .ok_or_else(|| anyhow::anyhow!("Cannot select {}", content.summary()))?;
+        self.backend.write_primary(&applied)?;
+        self.primary_hash = Some(Self::hash_content(&applied));
+        Ok(())
+    }
+
+    /// Write content received for `selection` to the local selection(s) it maps to
+    pub fn apply(&mut self, selection: Selection, content: &ClipboardContent) -> Result<()> {
+        match (selection, self.clipboard_target) {
+            (Selection::Primary, _) | (Selection::Clipboard, ClipboardTarget::Primary) => {
+                self.set_primary_content(content)
+            }
+            (Selection::Clipboard, ClipboardTarget::Clipboard) => {
+                self.set_clipboard_content(content)
+            }
+            (Selection::Clipboard, ClipboardTarget::Both) => {
+                self.set_clipboard_content(content)?;
+                // 图片和文件只写入剪贴板
+                if content.downgrade_for(&[ContentKind::Text]).is_none() {
+                    return Ok(());
+                }
+                self.set_primary_content(content)
+            }
+        }
+    }
+}
+
+fn image_data_to_png(img: &ImageData, limits: ImageLimits) -> Result<Vec<u8>> {
+    use image::{DynamicImage, ImageBuffer, RgbaImage};
+    use std::io::Cursor;
+
+    let ImageLimits {
+        max_size,
+        max_dimension,
+    } = limits;
+    let width = img.width as u32;
+    let height = img.height as u32;
+
+    // 检查图片尺寸
+    if width > max_dimension || height > max_dimension {
+        println!(
+            "Image dimensions too large: {}x{}, resizing to {}x{}",
+            width, height, max_dimension, max_dimension
+        );
+    }
+
+    // Convert ImageData bytes to RgbaImage
+    let img_buffer: RgbaImage = ImageBuffer::from_raw(width, height, img.bytes.to_vec())
+        .ok_or_else(|| anyhow::anyhow!("Failed to create image buffer"))?;
+
+    let mut dynamic_img = DynamicImage::ImageRgba8(img_buffer);
+
+    // 计算初始缩放尺寸
+    let mut target_width = width.min(max_dimension);
+    let mut target_height = height.min(max_dimension);
+
+    // 保持宽高比
+    if width > max_dimension || height > max_dimension {
+        let scale = (max_dimension as f64 / width.max(height) as f64).min(1.0);
+        target_width = (width as f64 * scale) as u32;
+        target_height = (height as f64 * scale) as u32;
+    }
+
+    // 估算大小并预先缩放
+    let estimated_size = target_width as usize * target_height as usize * 4;
+    if estimated_size > max_size * 2 {
+        let scale = ((max_size * 2) as f64 / estimated_size as f64).sqrt();
+        target_width = (target_width as f64 * scale) as u32;
+        target_height = (target_height as f64 * scale) as u32;
+
+        println!(
+            "Pre-scaling image from {}x{} to {}x{} for size limit",
+            width, height, target_width, target_height
+        );
+    }
+
+    // 如果需要缩放
+    if target_width != width || target_height != height {
+        dynamic_img = dynamic_img.resize(
+            target_width,
+            target_height,
+            image::imageops::FilterType::Lanczos3,
+        );
+    }
+
+    // 尝试编码，如果太大则继续缩小
+    let mut attempts = 0;
+    let max_attempts = 3;
+
+    loop {
+        attempts += 1;
+
+        // Encode as PNG
+        let mut png_data = Vec::new();
+        let mut cursor = Cursor::new(&mut png_data);
+        dynamic_img.write_to(&mut cursor, image::ImageFormat::Png)?;
+
+        // 检查大小
+        if png_data.len() <= max_size {
+            if attempts > 1 {
+                println!(
+                    "Successfully compressed image to {} bytes after {} attempts",
+                    png_data.len(),
+                    attempts
+                );
+            }
+            return Ok(png_data);
+        }
+
+        // 如果还是太大且未超过最大尝试次数
+        if attempts < max_attempts {
+            let current_width = dynamic_img.width();
+            let current_height = dynamic_img.height();
+            let scale = 0.7; // 每次缩小到 70%
+            let new_width = (current_width as f64 * scale) as u32;
+            let new_height = (current_height as f64 * scale) as u32;
+
+            println!(
+                "Image still too large ({} bytes), resizing from {}x{} to {}x{} (attempt {}/{})",
+                png_data.len(),
+                current_width,
+                current_height,
+                new_width,
+                new_height,
+                attempts,
+                max_attempts
+            );
+
+            dynamic_img = dynamic_img.resize(
+                new_width.max(100), // 最小保持 100px
+                new_height.max(100),
+                image::imageops::FilterType::Triangle, // 使用更快的算法
+            );
+        } else {
+            anyhow::bail!(
+                "Failed to compress image to size limit after {} attempts. Final size: {} bytes (max: {} bytes)",
+                attempts,
+                png_data.len(),
+                max_size
+            );
+        }
+    }
+}
+
+fn png_to_image_data(png_data: &[u8], width: u32, height: u32) -> Result<ImageData<'static>> {
+    use image::ImageReader;
+    use std::io::Cursor;
+
+    let img = ImageReader::new(Cursor::new(png_data))
+        .with_guessed_format()?
+        .decode()?;
+
+    let rgba = img.to_rgba8();
+    let bytes = rgba.into_raw();
+
+    Ok(ImageData {
+        width: width as usize,
+        height: height as usize,
+        bytes: std::borrow::Cow::Owned(bytes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::sync::FileEntry;
+
+    fn monitor() -> ClipboardMonitor {
+        ClipboardMonitor::with_backend(Box::new(MemoryBackend::new()))
+    }
+
+    fn stamped(text: &str, clock: u64) -> ClipboardMessage {
+        let mut message = ClipboardMessage::new(
+            ClipboardContent::Text(text.to_string()),
+            Some("peer".into()),
+        );
+        message.clock = Some(clock);
+        message
+    }
+
+    // Stamp of a local copy
+    fn copy(monitor: &mut ClipboardMonitor, text: &str) -> u64 {
+        let message = ClipboardMessage::new(ClipboardContent::Text(text.to_string()), None);
+        monitor.stamp(message).clock.unwrap()
+    }
+
+    #[test]
+    fn newest_change_wins() {
+        let mut monitor = monitor();
+        let clock = copy(&mut monitor, "local");
+        assert!(!monitor.accept(&stamped("older", clock - 1)));
+        assert!(monitor.accept(&stamped("newer", clock + 1)));
+    }
+
+    #[test]
+    fn stamps_far_in_the_future_are_rejected() {
+        let mut monitor = monitor();
+        let clock = copy(&mut monitor, "local");
+        assert!(!monitor.accept(&stamped("from the future", clock + 24 * 60 * 60 * 1000)));
+        assert!(!monitor.accept(&stamped("overflow", u64::MAX)));
+
+        // Local copies still win over everything that was rejected
+        let next = copy(&mut monitor, "next");
+        assert!(next < clock + 60_000);
+        assert!(!monitor.accept(&stamped("older", clock)));
+    }
+
+    #[test]
+    fn oversized_received_files_are_refused() {
+        let receive_dir =
+            std::env::temp_dir().join(format!("copi-test-{:x}", rand::random::<u64>()));
+        let mut monitor = monitor()
+            .with_receive_dir(receive_dir.clone())
+            .with_max_files_size(1000);
+        let files = ClipboardContent::Files(vec![FileEntry {
+            name: "large.bin".to_string(),
+            data: vec![0; 1001],
+        }]);
+        assert!(monitor.apply(Selection::Clipboard, &files).is_err());
+        assert!(!receive_dir.exists());
+    }
+}
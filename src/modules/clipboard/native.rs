@@ -0,0 +1,156 @@
+use super::{image_data_to_png, png_to_image_data, ClipboardBackend, ImageLimits};
+use crate::modules::protocol::ContentKind;
+use crate::modules::sync::{
+    ClipboardContent, Representation, Selection, HTML_MIME, PNG_MIME, TEXT_MIME,
+};
+use crate::modules::watcher::{self, ChangeSender};
+use anyhow::Result;
+use arboard::Clipboard;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Native clipboard through arboard (X11, macOS, Windows)
+pub struct ArboardBackend {
+    clipboard: Clipboard,
+    limits: ImageLimits,
+}
+
+impl ArboardBackend {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            clipboard: Clipboard::new()?,
+            limits: ImageLimits::default(),
+        })
+    }
+}
+
+impl ClipboardBackend for ArboardBackend {
+    fn name(&self) -> &'static str {
+        "arboard"
+    }
+
+    fn supported_kinds(&self) -> Vec<ContentKind> {
+        // arboard 只能同时写入 HTML 和纯文本，多种格式的内容写入时会降级
+        vec![
+            ContentKind::Text,
+            ContentKind::Image,
+            ContentKind::Html,
+            ContentKind::Files,
+        ]
+    }
+
+    fn read(&mut self) -> Result<ClipboardContent> {
+        let clipboard = &mut self.clipboard;
+        let mut formats = Vec::new();
+
+        if let Ok(img) = clipboard.get_image() {
+            match image_data_to_png(&img, self.limits) {
+                Ok(png_data) => formats.push(Representation::new(PNG_MIME, png_data)),
+                Err(e) => eprintln!("Failed to process image from clipboard: {}", e),
+            }
+        }
+        // 浏览器等应用复制时同时提供 HTML 和纯文本
+        if let Ok(html) = clipboard.get().html() {
+            formats.push(Representation::new(HTML_MIME, html.into_bytes()));
+        }
+        match clipboard.get_text() {
+            Ok(text) => formats.push(Representation::new(TEXT_MIME, text.into_bytes())),
+            Err(e) if formats.is_empty() => {
+                anyhow::bail!("Failed to get clipboard content: {}", e)
+            }
+            Err(_) => {}
+        }
+
+        ClipboardContent::from_representations(formats)
+            .ok_or_else(|| anyhow::anyhow!("Clipboard is empty"))
+    }
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<()> {
+        match content {
+            ClipboardContent::Text(text) => {
+                self.clipboard
+                    .set_text(text)
+                    .map_err(|e| anyhow::anyhow!("Failed to set clipboard text: {}", e))?;
+            }
+            ClipboardContent::Image {
+                data,
+                width,
+                height,
+            } => {
+                // Convert to ImageData
+                let img_data = png_to_image_data(data, *width, *height)?;
+
+                self.clipboard
+                    .set_image(img_data)
+                    .map_err(|e| anyhow::anyhow!("Failed to set clipboard image: {}", e))?;
+            }
+            ClipboardContent::Html { html, text } => {
+                // 同时提供纯文本，不支持 HTML 的应用也能粘贴
+                let text = (!text.is_empty()).then_some(text.as_str());
+                self.clipboard
+                    .set()
+                    .html(html.as_str(), text)
+                    .map_err(|e| anyhow::anyhow!("Failed to set clipboard html: {}", e))?;
+            }
+            other => anyhow::bail!("Unsupported content: {}", other.summary()),
+        }
+        Ok(())
+    }
+
+    fn read_files(&mut self) -> Result<Vec<PathBuf>> {
+        // Fails whenever the clipboard holds something other than files
+        Ok(self.clipboard.get().file_list().unwrap_or_default())
+    }
+
+    fn write_files(&mut self, paths: &[PathBuf]) -> Result<()> {
+        self.clipboard
+            .set()
+            .file_list(paths)
+            .map_err(|e| anyhow::anyhow!("Failed to set clipboard files: {}", e))
+    }
+
+    fn set_image_limits(&mut self, limits: ImageLimits) {
+        self.limits = limits;
+    }
+
+    fn watch(&self, tx: ChangeSender, poll_interval: Duration) -> JoinHandle<()> {
+        watcher::spawn_native_watcher(tx, poll_interval, Selection::Clipboard)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn supports_primary(&self) -> bool {
+        true
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_primary(&mut self) -> Result<ClipboardContent> {
+        use arboard::{GetExtLinux, LinuxClipboardKind};
+
+        self.clipboard
+            .get()
+            .clipboard(LinuxClipboardKind::Primary)
+            .text()
+            .map(ClipboardContent::Text)
+            .map_err(|e| anyhow::anyhow!("Failed to get primary selection: {}", e))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_primary(&mut self, content: &ClipboardContent) -> Result<()> {
+        use arboard::{LinuxClipboardKind, SetExtLinux};
+
+        let ClipboardContent::Text(text) = content else {
+            anyhow::bail!("Unsupported content: {}", content.summary());
+        };
+        self.clipboard
+            .set()
+            .clipboard(LinuxClipboardKind::Primary)
+            .text(text.clone())
+            .map_err(|e| anyhow::anyhow!("Failed to set primary selection: {}", e))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch_primary(&self, tx: ChangeSender, poll_interval: Duration) -> JoinHandle<()> {
+        watcher::spawn_native_watcher(tx, poll_interval, Selection::Primary)
+    }
+}
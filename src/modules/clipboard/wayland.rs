@@ -0,0 +1,448 @@
+use super::{image_data_to_png, ClipboardBackend, ImageLimits};
+use crate::modules::protocol::ContentKind;
+use crate::modules::sync::{ClipboardContent, Representation, Selection, PNG_MIME, TEXT_MIME};
+use crate::modules::watcher::{self, ChangeSender};
+use anyhow::Result;
+use arboard::ImageData;
+use std::cell::OnceCell;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// wl-clipboard command line tools (`wl-paste`/`wl-copy`)
+#[derive(Default)]
+pub struct WlClipboardBackend {
+    limits: ImageLimits,
+    // Checked on first use, connecting to the compositor isn't free
+    data_control: OnceCell<bool>,
+    // Types listed by read_files(), reused by the read() that follows it
+    listed_types: Option<Vec<String>>,
+}
+
+impl WlClipboardBackend {
+    pub fn is_available() -> bool {
+        Command::new("wl-paste").arg("--version").output().is_ok()
+    }
+
+    fn wl_paste(selection: Selection) -> Result<String> {
+        let mut command = Command::new("wl-paste");
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        let output = command.arg("--no-newline").output()?;
+
+        if output.status.success() {
+            Ok(String::from_utf8(output.stdout)?)
+        } else {
+            anyhow::bail!("wl-paste failed")
+        }
+    }
+
+    fn wl_paste_image(&self) -> Result<ClipboardContent> {
+        let output = Command::new("wl-paste")
+            .arg("--type")
+            .arg("image/png")
+            .output()?;
+
+        if output.status.success() && !output.stdout.is_empty() {
+            let (data, width, height) = self.fit_png(output.stdout)?;
+            Ok(ClipboardContent::Image {
+                data,
+                width,
+                height,
+            })
+        } else {
+            anyhow::bail!("wl-paste image failed")
+        }
+    }
+
+    // PNG data within the image limits, with its dimensions
+    fn fit_png(&self, png_data: Vec<u8>) -> Result<(Vec<u8>, u32, u32)> {
+        use image::ImageReader;
+        use std::io::Cursor;
+
+        let img = ImageReader::new(Cursor::new(&png_data))
+            .with_guessed_format()?
+            .decode()?;
+        let width = img.width();
+        let height = img.height();
+
+        // 检查大小
+        if png_data.len() <= self.limits.max_size {
+            // 大小合适，直接使用
+            return Ok((png_data, width, height));
+        }
+        println!(
+            "Clipboard image too large ({} bytes), reprocessing...",
+            png_data.len()
+        );
+
+        // 转换为 ImageData 格式并使用我们的压缩逻辑
+        let rgba = img.to_rgba8();
+        let img_data = ImageData {
+            width: width as usize,
+            height: height as usize,
+            bytes: std::borrow::Cow::Owned(rgba.into_raw()),
+        };
+
+        // 使用我们的压缩函数
+        let compressed_png = image_data_to_png(&img_data, self.limits)?;
+        Ok((compressed_png, width, height))
+    }
+
+    fn wl_paste_types() -> Result<Vec<String>> {
+        let output = Command::new("wl-paste").arg("--list-types").output()?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::to_string)
+                .collect())
+        } else {
+            anyhow::bail!("wl-paste list-types failed")
+        }
+    }
+
+    fn wl_paste_type(mime: &str) -> Result<Vec<u8>> {
+        let output = Command::new("wl-paste").arg("--type").arg(mime).output()?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            anyhow::bail!("wl-paste {} failed", mime)
+        }
+    }
+
+    // Read every format in `formats` as one clipboard item
+    fn wl_paste_formats(&self, formats: &[(&str, &str)]) -> Result<ClipboardContent> {
+        let mut representations = Vec::new();
+        let mut total = 0;
+        for &(offered, mime) in formats {
+            let mut data = Self::wl_paste_type(offered)?;
+            if mime == PNG_MIME {
+                data = self.fit_png(data)?.0;
+            }
+            if total + data.len() > self.limits.max_size {
+                println!("Skipping {} of the copy: too large", mime);
+                continue;
+            }
+            total += data.len();
+            representations.push(Representation::new(mime, data));
+        }
+        ClipboardContent::from_representations(representations)
+            .ok_or_else(|| anyhow::anyhow!("Clipboard is empty"))
+    }
+
+    fn wl_copy_text(content: &str, selection: Selection) -> Result<()> {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let mut command = Command::new("wl-copy");
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        let mut child = command.stdin(Stdio::piped()).spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(content.as_bytes())?;
+        }
+
+        let status = child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            anyhow::bail!("wl-copy failed")
+        }
+    }
+
+    fn wl_copy_image(png_data: &[u8]) -> Result<()> {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let mut child = Command::new("wl-copy")
+            .arg("--type")
+            .arg("image/png")
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(png_data)?;
+        }
+
+        let status = child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            anyhow::bail!("wl-copy image failed")
+        }
+    }
+
+    fn wl_paste_uri_list() -> Result<Vec<PathBuf>> {
+        let output = Command::new("wl-paste")
+            .arg("--type")
+            .arg("text/uri-list")
+            .output()?;
+        if output.status.success() {
+            Ok(parse_uri_list(&String::from_utf8_lossy(&output.stdout)))
+        } else {
+            anyhow::bail!("wl-paste uri-list failed")
+        }
+    }
+
+    fn wl_copy_uri_list(paths: &[PathBuf]) -> Result<()> {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let mut child = Command::new("wl-copy")
+            .arg("--type")
+            .arg("text/uri-list")
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(uri_list(paths).as_bytes())?;
+        }
+
+        let status = child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            anyhow::bail!("wl-copy uri-list failed")
+        }
+    }
+
+    // wl-copy offers a single type, several at once need the data-control protocol
+    fn copy_formats(formats: &[Representation]) -> Result<()> {
+        use wl_clipboard_rs::copy::{MimeSource, MimeType, Options, Source};
+
+        let sources = formats
+            .iter()
+            .map(|format| MimeSource {
+                source: Source::Bytes(format.data.clone().into_boxed_slice()),
+                // 纯文本同时以常见的别名提供
+                mime_type: if format.mime == TEXT_MIME {
+                    MimeType::Text
+                } else {
+                    MimeType::Specific(format.mime.clone())
+                },
+            })
+            .collect();
+        Options::new()
+            .copy_multi(sources)
+            .map_err(|e| anyhow::anyhow!("Failed to set clipboard formats: {}", e))
+    }
+
+    // Whether the compositor supports the data-control protocol (wlroots based ones,
+    // KDE, GNOME 48+)
+    fn has_data_control() -> bool {
+        use wl_clipboard_rs::paste::{get_mime_types, ClipboardType, Error, Seat};
+
+        matches!(
+            get_mime_types(ClipboardType::Regular, Seat::Unspecified),
+            Ok(_) | Err(Error::ClipboardEmpty | Error::NoMimeType)
+        )
+    }
+
+    fn wl_copy_html(html: &str) -> Result<()> {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let mut child = Command::new("wl-copy")
+            .arg("--type")
+            .arg("text/html")
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(html.as_bytes())?;
+        }
+
+        let status = child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            anyhow::bail!("wl-copy html failed")
+        }
+    }
+}
+
+impl ClipboardBackend for WlClipboardBackend {
+    fn name(&self) -> &'static str {
+        "wl-clipboard"
+    }
+
+    fn supported_kinds(&self) -> Vec<ContentKind> {
+        let mut kinds = vec![
+            ContentKind::Text,
+            ContentKind::Image,
+            ContentKind::Html,
+            ContentKind::Files,
+        ];
+        if *self.data_control.get_or_init(Self::has_data_control) {
+            kinds.push(ContentKind::Multi);
+        }
+        kinds
+    }
+
+    fn read(&mut self) -> Result<ClipboardContent> {
+        // 同时提供多种格式时（例如从浏览器复制），全部读取
+        let types = match self.listed_types.take() {
+            Some(types) => types,
+            None => Self::wl_paste_types().unwrap_or_default(),
+        };
+        let formats = captured_formats(&types);
+        if formats.len() > 1 {
+            match self.wl_paste_formats(&formats) {
+                Ok(content) => return Ok(content),
+                Err(e) => eprintln!("Failed to read clipboard formats: {}", e),
+            }
+        }
+
+        // Try to get image first
+        match self.wl_paste_image() {
+            Ok(img_data) => Ok(img_data),
+            Err(e) => {
+                // 记录图片获取失败，但不是错误（可能剪贴板中没有图片）
+                if !e.to_string().contains("wl-paste image failed") {
+                    eprintln!("Failed to get image from clipboard: {}", e);
+                }
+                // Fall back to text
+                Self::wl_paste(Selection::Clipboard).map(ClipboardContent::Text)
+            }
+        }
+    }
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<()> {
+        match content {
+            ClipboardContent::Text(text) => Self::wl_copy_text(text, Selection::Clipboard),
+            ClipboardContent::Image { data, .. } => Self::wl_copy_image(data),
+            ClipboardContent::Html { html, text: _ } => Self::wl_copy_html(html),
+            ClipboardContent::Multi(formats) => Self::copy_formats(formats),
+            other => anyhow::bail!("Unsupported content: {}", other.summary()),
+        }
+    }
+
+    fn read_files(&mut self) -> Result<Vec<PathBuf>> {
+        self.listed_types = None;
+        let types = Self::wl_paste_types()?;
+        if !types.iter().any(|mime| mime == "text/uri-list") {
+            // No files, read() is called next and needs the same list
+            self.listed_types = Some(types);
+            return Ok(Vec::new());
+        }
+        Self::wl_paste_uri_list()
+    }
+
+    fn write_files(&mut self, paths: &[PathBuf]) -> Result<()> {
+        Self::wl_copy_uri_list(paths)
+    }
+
+    fn set_image_limits(&mut self, limits: ImageLimits) {
+        self.limits = limits;
+    }
+
+    fn watch(&self, tx: ChangeSender, poll_interval: Duration) -> JoinHandle<()> {
+        watcher::spawn_wl_paste_watcher(tx, poll_interval, Selection::Clipboard)
+    }
+
+    fn supports_primary(&self) -> bool {
+        true
+    }
+
+    fn read_primary(&mut self) -> Result<ClipboardContent> {
+        Self::wl_paste(Selection::Primary).map(ClipboardContent::Text)
+    }
+
+    fn write_primary(&mut self, content: &ClipboardContent) -> Result<()> {
+        match content {
+            ClipboardContent::Text(text) => Self::wl_copy_text(text, Selection::Primary),
+            other => anyhow::bail!("Unsupported content: {}", other.summary()),
+        }
+    }
+
+    fn watch_primary(&self, tx: ChangeSender, poll_interval: Duration) -> JoinHandle<()> {
+        watcher::spawn_wl_paste_watcher(tx, poll_interval, Selection::Primary)
+    }
+}
+
+// Formats of the current copy worth keeping, as (offered type, MIME type it is kept as)
+fn captured_formats(types: &[String]) -> Vec<(&str, &str)> {
+    let mut formats = Vec::new();
+    // 纯文本通常以多个别名提供，只读取一次
+    let text = ["text/plain;charset=utf-8", "text/plain"]
+        .into_iter()
+        .find(|text| types.iter().any(|mime| mime == text));
+    if let Some(text) = text {
+        formats.push((text, TEXT_MIME));
+    }
+    for mime in types {
+        let skipped = !mime.contains('/') // X11 targets such as UTF8_STRING
+            || mime.starts_with("text/plain")
+            // Other image types are conversions of the same picture
+            || (mime.starts_with("image/") && mime != PNG_MIME)
+            || mime == "application/x-qt-image"
+            // Copied files are read by read_files
+            || mime == "text/uri-list"
+            || mime == "x-special/gnome-copied-files";
+        if !skipped {
+            formats.push((mime.as_str(), mime.as_str()));
+        }
+    }
+    formats
+}
+
+// Local paths of the file:// URIs in a text/uri-list (RFC 2483), comments and other
+// schemes are skipped
+fn parse_uri_list(list: &str) -> Vec<PathBuf> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.strip_prefix("file://"))
+        // file://host/path, the host is usually empty or localhost
+        .filter_map(|rest| rest.find('/').map(|start| &rest[start..]))
+        .map(|path| PathBuf::from(OsString::from_vec(percent_decode(path))))
+        .collect()
+}
+
+fn uri_list(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| format!("file://{}\r\n", percent_encode(path)))
+        .collect()
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
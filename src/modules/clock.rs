@@ -0,0 +1,82 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Received stamps may be this far ahead of our wall clock. A peer with a clock set far
+// into the future would otherwise win every conflict until ours caught up.
+const MAX_DRIFT: Duration = Duration::from_secs(5 * 60);
+
+/// Hybrid logical clock: milliseconds of wall time, but never behind anything seen before.
+///
+/// Stamps follow the wall clock while machines agree on the time and still order
+/// causally related changes when they don't, so a copy made after receiving another
+/// one always carries the larger stamp.
+#[derive(Debug, Default)]
+pub struct HybridClock {
+    last: u64,
+}
+
+impl HybridClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stamp for a local change
+    pub fn tick(&mut self) -> u64 {
+        self.last = now_millis().max(self.last.saturating_add(1));
+        self.last
+    }
+
+    /// Move past a stamp received from another machine
+    pub fn observe(&mut self, stamp: u64) {
+        self.last = self.last.max(stamp);
+    }
+}
+
+/// Whether a received stamp is within the allowed drift of our wall clock
+pub fn plausible(stamp: u64) -> bool {
+    stamp <= now_millis().saturating_add(MAX_DRIFT.as_millis() as u64)
+}
+
+/// Position of a clipboard change in the global order: the later stamp wins and the
+/// origin id breaks ties, so every machine picks the same winner.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub clock: u64,
+    pub origin: String,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_move_past_everything_observed() {
+        let mut clock = HybridClock::new();
+        let first = clock.tick();
+        clock.observe(first + 60_000);
+        assert!(clock.tick() > first + 60_000);
+    }
+
+    #[test]
+    fn huge_stamps_do_not_overflow() {
+        let mut clock = HybridClock::new();
+        clock.observe(u64::MAX);
+        assert_eq!(clock.tick(), u64::MAX);
+        assert_eq!(clock.tick(), u64::MAX);
+    }
+
+    #[test]
+    fn stamps_far_in_the_future_are_implausible() {
+        let now = now_millis();
+        assert!(plausible(now));
+        assert!(plausible(now + 60_000));
+        assert!(!plausible(now + 2 * MAX_DRIFT.as_millis() as u64));
+        assert!(!plausible(u64::MAX));
+    }
+}
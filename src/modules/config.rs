@@ -0,0 +1,429 @@
+use crate::modules::clipboard::{files, BackendKind, ClipboardTarget, ImageLimits};
+use crate::modules::history::HistoryLimits;
+use crate::modules::protocol::ContentKind;
+use crate::modules::sync::{ClipboardContent, HTML_MIME, TEXT_MIME};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings from the config file.
+///
+/// Everything is optional: command line flags take precedence, built-in defaults
+/// apply to whatever neither of them sets.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    // Server
+    pub addr: Option<SocketAddr>,
+    pub websocket: Option<SocketAddr>,
+    pub relay_only: Option<bool>,
+    pub relay_history: Option<bool>,
+    pub replay: Option<bool>,
+    pub mdns: Option<bool>,
+    pub room_tokens: Option<HashMap<String, String>>,
+    pub hello_timeout_ms: Option<u64>,
+    // Client
+    pub server: Option<SocketAddr>,
+    pub url: Option<String>,
+    pub p2p: Option<bool>,
+    pub listen: Option<SocketAddr>,
+    pub peers: Option<Vec<SocketAddr>>,
+    pub tls_ca: Option<PathBuf>,
+    pub tls_fingerprint: Option<String>,
+    pub tls_server_name: Option<String>,
+    pub room: Option<String>,
+    pub room_token: Option<String>,
+    // Both
+    pub group: Option<String>,
+    pub tls: Option<bool>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub passphrase: Option<String>,
+    pub passphrase_file: Option<PathBuf>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub backend: Option<BackendKind>,
+    pub primary: Option<bool>,
+    pub clipboard_target: Option<ClipboardTarget>,
+    pub receive_dir: Option<PathBuf>,
+    pub history: Option<bool>,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_timeout: Option<u64>,
+    pub name: Option<String>,
+    pub poll_interval_ms: Option<u64>,
+    pub retry_delay: Option<u64>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub filters: Filters,
+}
+
+impl Profile {
+    /// Fill whatever this profile leaves unset from `base`
+    fn or(self, base: Profile) -> Profile {
+        Profile {
+            addr: self.addr.or(base.addr),
+            websocket: self.websocket.or(base.websocket),
+            relay_only: self.relay_only.or(base.relay_only),
+            relay_history: self.relay_history.or(base.relay_history),
+            replay: self.replay.or(base.replay),
+            mdns: self.mdns.or(base.mdns),
+            room_tokens: self.room_tokens.or(base.room_tokens),
+            hello_timeout_ms: self.hello_timeout_ms.or(base.hello_timeout_ms),
+            server: self.server.or(base.server),
+            url: self.url.or(base.url),
+            p2p: self.p2p.or(base.p2p),
+            listen: self.listen.or(base.listen),
+            peers: self.peers.or(base.peers),
+            tls_ca: self.tls_ca.or(base.tls_ca),
+            tls_fingerprint: self.tls_fingerprint.or(base.tls_fingerprint),
+            tls_server_name: self.tls_server_name.or(base.tls_server_name),
+            room: self.room.or(base.room),
+            room_token: self.room_token.or(base.room_token),
+            group: self.group.or(base.group),
+            tls: self.tls.or(base.tls),
+            tls_cert: self.tls_cert.or(base.tls_cert),
+            tls_key: self.tls_key.or(base.tls_key),
+            passphrase: self.passphrase.or(base.passphrase),
+            passphrase_file: self.passphrase_file.or(base.passphrase_file),
+            token: self.token.or(base.token),
+            token_file: self.token_file.or(base.token_file),
+            backend: self.backend.or(base.backend),
+            primary: self.primary.or(base.primary),
+            clipboard_target: self.clipboard_target.or(base.clipboard_target),
+            receive_dir: self.receive_dir.or(base.receive_dir),
+            history: self.history.or(base.history),
+            heartbeat_interval: self.heartbeat_interval.or(base.heartbeat_interval),
+            heartbeat_timeout: self.heartbeat_timeout.or(base.heartbeat_timeout),
+            name: self.name.or(base.name),
+            poll_interval_ms: self.poll_interval_ms.or(base.poll_interval_ms),
+            retry_delay: self.retry_delay.or(base.retry_delay),
+            limits: self.limits.or(base.limits),
+            filters: self.filters.or(base.filters),
+        }
+    }
+
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval_ms.map(Duration::from_millis)
+    }
+
+    pub fn hello_timeout(&self) -> Option<Duration> {
+        self.hello_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn retry_delay(&self) -> Option<Duration> {
+        self.retry_delay.map(Duration::from_secs)
+    }
+
+    // "~/" in paths refers to the home directory, like on the command line
+    fn expand_paths(mut self) -> Profile {
+        for path in [
+            &mut self.tls_ca,
+            &mut self.tls_cert,
+            &mut self.tls_key,
+            &mut self.passphrase_file,
+            &mut self.token_file,
+            &mut self.receive_dir,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let (Ok(rest), Some(home)) = (path.strip_prefix("~"), dirs::home_dir()) {
+                *path = home.join(rest);
+            }
+        }
+        self
+    }
+}
+
+/// `[limits]` section, sizes in MB
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub max_image_mb: Option<usize>,
+    pub max_image_dimension: Option<u32>,
+    pub max_files_mb: Option<usize>,
+    pub history_entries: Option<usize>,
+    pub history_mb: Option<usize>,
+    pub history_days: Option<u64>,
+}
+
+impl Limits {
+    fn or(self, base: Limits) -> Limits {
+        Limits {
+            max_image_mb: self.max_image_mb.or(base.max_image_mb),
+            max_image_dimension: self.max_image_dimension.or(base.max_image_dimension),
+            max_files_mb: self.max_files_mb.or(base.max_files_mb),
+            history_entries: self.history_entries.or(base.history_entries),
+            history_mb: self.history_mb.or(base.history_mb),
+            history_days: self.history_days.or(base.history_days),
+        }
+    }
+
+    pub fn image(&self) -> ImageLimits {
+        let default = ImageLimits::default();
+        ImageLimits {
+            max_size: self
+                .max_image_mb
+                .map_or(default.max_size, |mb| mb * 1024 * 1024),
+            max_dimension: self.max_image_dimension.unwrap_or(default.max_dimension),
+        }
+    }
+
+    /// Total size of the files copied at once
+    pub fn max_files_size(&self) -> usize {
+        self.max_files_mb
+            .map_or(files::MAX_FILES_SIZE, |mb| mb * 1024 * 1024)
+    }
+
+    pub fn history(&self) -> HistoryLimits {
+        let default = HistoryLimits::default();
+        HistoryLimits {
+            max_entries: self.history_entries.unwrap_or(default.max_entries),
+            max_bytes: self
+                .history_mb
+                .map_or(default.max_bytes, |mb| mb * 1024 * 1024),
+            max_age: self.history_days.map_or(default.max_age, |days| {
+                Duration::from_secs(days * 24 * 60 * 60)
+            }),
+        }
+    }
+}
+
+/// `[filters]` section: which local clipboard content is shared and which
+/// received content is applied
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Filters {
+    // Only these kinds are synced, all of them when unset
+    pub kinds: Option<Vec<FilterKind>>,
+    // Text and HTML containing any of these strings is not synced
+    pub ignore: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterKind {
+    Text,
+    Image,
+    Html,
+    Files,
+}
+
+impl Filters {
+    fn or(self, base: Filters) -> Filters {
+        Filters {
+            kinds: self.kinds.or(base.kinds),
+            ignore: self.ignore.or(base.ignore),
+        }
+    }
+
+    /// The part of `content` that may be synced, None when nothing is
+    pub fn filter(&self, content: ClipboardContent) -> Option<ClipboardContent> {
+        let content = match &self.kinds {
+            Some(kinds) => content.retain_kinds(|kind| match kind {
+                ContentKind::Text => kinds.contains(&FilterKind::Text),
+                ContentKind::Image => kinds.contains(&FilterKind::Image),
+                ContentKind::Html => kinds.contains(&FilterKind::Html),
+                ContentKind::Files => kinds.contains(&FilterKind::Files),
+                // Multi-format items are filtered format by format
+                ContentKind::Multi => true,
+                // Can't look inside, whoever decrypts it filters it
+                ContentKind::Encrypted | ContentKind::Unknown => true,
+            })?,
+            None => content,
+        };
+
+        let texts: Vec<Cow<str>> = match &content {
+            ClipboardContent::Text(text) => vec![Cow::from(text)],
+            ClipboardContent::Html { html, .. } => vec![Cow::from(html)],
+            ClipboardContent::Multi(_) => [TEXT_MIME, HTML_MIME]
+                .iter()
+                .filter_map(|mime| content.representation(mime))
+                .map(String::from_utf8_lossy)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let ignored = texts.iter().any(|text| {
+            self.ignore
+                .iter()
+                .flatten()
+                .any(|pattern| text.contains(pattern.as_str()))
+        });
+        (!ignored).then_some(content)
+    }
+}
+
+/// The config file: top-level settings apply to every profile, `[profiles.<name>]`
+/// tables override them.
+#[derive(Debug, Default)]
+pub struct Config {
+    base: Profile,
+    profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    pub fn default_path() -> Result<PathBuf> {
+        let dir = dirs::config_dir()
+            .ok_or_else(|| anyhow::anyhow!("Cannot determine config directory"))?;
+        Ok(dir.join("copi").join("config.toml"))
+    }
+
+    /// Read the config at `path`, or the default location when not given.
+    ///
+    /// A missing file at the default location is the same as an empty one.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => (Self::default_path()?, false),
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default())
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let profiles = match table.remove("profiles") {
+            Some(profiles) => profiles.try_into()?,
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            base: toml::Value::Table(table).try_into()?,
+            profiles,
+        })
+    }
+
+    /// Settings of the named profile on top of the top-level ones
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        let profile = match name {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("No profile named '{}' in the config file", name))?
+                .clone()
+                .or(self.base.clone()),
+            None => self.base.clone(),
+        };
+        Ok(profile.expand_paths())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        server = "10.0.0.1:9527"
+        name = "laptop"
+        heartbeat_interval = 5
+
+        [limits]
+        max_image_mb = 10
+        history_entries = 50
+
+        [filters]
+        ignore = ["password"]
+
+        [profiles.work]
+        server = "10.1.0.1:9527"
+        tls = true
+        tls_ca = "~/certs/work.pem"
+
+        [profiles.work.limits]
+        max_image_mb = 2
+    "#;
+
+    #[test]
+    fn profile_settings_override_the_top_level() {
+        let config = Config::parse(CONFIG).unwrap();
+        let work = config.profile(Some("work")).unwrap();
+        assert_eq!(work.server, Some("10.1.0.1:9527".parse().unwrap()));
+        assert_eq!(work.tls, Some(true));
+        // Everything else comes from the top level, tables field by field
+        assert_eq!(work.name.as_deref(), Some("laptop"));
+        assert_eq!(work.heartbeat_interval, Some(5));
+        assert_eq!(work.limits.max_image_mb, Some(2));
+        assert_eq!(work.limits.history_entries, Some(50));
+        assert_eq!(work.filters.ignore, Some(vec!["password".to_string()]));
+
+        let base = config.profile(None).unwrap();
+        assert_eq!(base.server, Some("10.0.0.1:9527".parse().unwrap()));
+        assert_eq!(base.tls, None);
+        assert_eq!(base.limits.max_image_mb, Some(10));
+    }
+
+    #[test]
+    fn unknown_profiles_and_settings_are_errors() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert!(config.profile(Some("home")).is_err());
+        assert!(Config::parse("sever = \"10.0.0.1:9527\"").is_err());
+        assert!(Config::parse("[profiles.work]\nport = 1").is_err());
+        assert!(Config::parse("[limits]\nmax_image = 1").is_err());
+    }
+
+    #[test]
+    fn home_directory_is_expanded() {
+        let Some(home) = dirs::home_dir() else {
+            return;
+        };
+        let work = Config::parse(CONFIG)
+            .unwrap()
+            .profile(Some("work"))
+            .unwrap();
+        assert_eq!(work.tls_ca, Some(home.join("certs/work.pem")));
+    }
+
+    #[test]
+    fn unset_limits_keep_the_defaults() {
+        let limits = Config::parse(CONFIG).unwrap().profile(None).unwrap().limits;
+        assert_eq!(limits.image().max_size, 10 * 1024 * 1024);
+        assert_eq!(
+            limits.image().max_dimension,
+            ImageLimits::default().max_dimension
+        );
+        let history = limits.history();
+        assert_eq!(history.max_entries, 50);
+        assert_eq!(history.max_bytes, HistoryLimits::default().max_bytes);
+        assert_eq!(limits.max_files_size(), files::MAX_FILES_SIZE);
+    }
+
+    #[test]
+    fn missing_config_file_is_only_an_error_when_given() {
+        let path = std::env::temp_dir().join(format!("copi-test-{:x}.toml", rand::random::<u64>()));
+        assert!(Config::load(Some(&path)).is_err());
+
+        std::fs::write(&path, "name = \"desktop\"").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(
+            config.profile(None).unwrap().name.as_deref(),
+            Some("desktop")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn filters_drop_ignored_text_and_unwanted_kinds() {
+        let filters = Filters {
+            kinds: Some(vec![FilterKind::Text]),
+            ignore: Some(vec!["password".to_string()]),
+        };
+        let text = |text: &str| ClipboardContent::Text(text.to_string());
+        assert!(filters.filter(text("hello")).is_some());
+        assert!(filters.filter(text("my password is")).is_none());
+        let html = ClipboardContent::Html {
+            html: "<b>hi</b>".to_string(),
+            text: "hi".to_string(),
+        };
+        assert!(filters.filter(html).is_none());
+    }
+}
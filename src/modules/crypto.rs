@@ -0,0 +1,291 @@
+use crate::modules::sync::{
+    ClipboardContent, ClipboardMessage, COMPRESSION_THRESHOLD, MAX_TRANSFER_SIZE,
+};
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::io::Read;
+
+// Peers must derive the same key from the passphrase alone, so the salt is fixed
+const KDF_SALT: &[u8] = b"copi-e2e-clipboard-v1";
+// First byte of the plaintext: the content serialized with MessagePack, as it is or
+// compressed with zstd. Older clients sealed JSON without a version byte, which starts
+// with `{` or `"` instead.
+const PLAINTEXT_MSGPACK: u8 = 1;
+const PLAINTEXT_MSGPACK_ZSTD: u8 = 2;
+
+/// End-to-end encryption of clipboard content with a key shared by all clients.
+///
+/// The server (relay) only ever sees `ClipboardContent::Encrypted`. The ciphertext is
+/// bound to the sender, selection, clock and room of its message, so a relay can't
+/// re-tag it as another device or room, and a replayed copy loses against the newer
+/// clock already seen.
+#[derive(Clone)]
+pub struct ContentCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl ContentCipher {
+    pub fn from_passphrase(passphrase: &str) -> Result<Self> {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), KDF_SALT, &mut key)
+            .map_err(|e| anyhow::anyhow!("Failed to derive key: {}", e))?;
+
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Encrypt the content of a message before it is sent.
+    ///
+    /// The message must already be stamped, its clock is part of the associated data.
+    pub fn seal(&self, message: &mut ClipboardMessage, room: Option<&str>) -> Result<()> {
+        message.content = self.encrypt(&message.content, &associated_data(message, room)?)?;
+        Ok(())
+    }
+
+    /// Decrypt the content of a message sent by a peer, in the room it arrived in.
+    ///
+    /// Plaintext content is rejected as well, otherwise anyone between the peers
+    /// (e.g. the relay) could inject clipboard data.
+    pub fn open(&self, message: &mut ClipboardMessage, room: Option<&str>) -> Result<()> {
+        message.content = self.decrypt(&message.content, &associated_data(message, room)?)?;
+        Ok(())
+    }
+
+    fn encrypt(&self, content: &ClipboardContent, aad: &[u8]) -> Result<ClipboardContent> {
+        let plaintext = serialize(content)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt content"))?;
+
+        Ok(ClipboardContent::Encrypted {
+            nonce: nonce.to_vec(),
+            data: ciphertext,
+        })
+    }
+
+    fn decrypt(&self, content: &ClipboardContent, aad: &[u8]) -> Result<ClipboardContent> {
+        let ClipboardContent::Encrypted { nonce, data } = content else {
+            anyhow::bail!("Received unencrypted {}", content.summary());
+        };
+
+        if nonce.len() != 24 {
+            anyhow::bail!("Invalid nonce length: {}", nonce.len());
+        }
+        let plaintext = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: data, aad })
+            .map_err(|_| {
+                anyhow::anyhow!("Failed to decrypt content (wrong passphrase or altered message?)")
+            })?;
+
+        let content = deserialize(&plaintext)?;
+        if matches!(content, ClipboardContent::Encrypted { .. }) {
+            anyhow::bail!("Nested encrypted content");
+        }
+        Ok(content)
+    }
+}
+
+// Ciphertext doesn't compress, so content is compressed before it is sealed
+fn serialize(content: &ClipboardContent) -> Result<Vec<u8>> {
+    let data = rmp_serde::to_vec_named(content).context("Failed to serialize content")?;
+    if content.compressible() && data.len() > COMPRESSION_THRESHOLD {
+        let compressed = zstd::bulk::compress(&data, 0).context("Failed to compress content")?;
+        if compressed.len() < data.len() {
+            return Ok([&[PLAINTEXT_MSGPACK_ZSTD], compressed.as_slice()].concat());
+        }
+    }
+    Ok([&[PLAINTEXT_MSGPACK], data.as_slice()].concat())
+}
+
+fn deserialize(plaintext: &[u8]) -> Result<ClipboardContent> {
+    match plaintext.first() {
+        Some(&PLAINTEXT_MSGPACK) => {
+            rmp_serde::from_slice(&plaintext[1..]).context("Failed to deserialize content")
+        }
+        Some(&PLAINTEXT_MSGPACK_ZSTD) => {
+            // Bounded like a reassembled transfer, so a small payload can't expand without limit
+            let mut data = Vec::new();
+            zstd::stream::read::Decoder::new(&plaintext[1..])
+                .and_then(|decoder| {
+                    decoder
+                        .take(MAX_TRANSFER_SIZE as u64 + 1)
+                        .read_to_end(&mut data)
+                })
+                .context("Failed to decompress content")?;
+            if data.len() > MAX_TRANSFER_SIZE {
+                anyhow::bail!(
+                    "Decompressed content larger than {} bytes",
+                    MAX_TRANSFER_SIZE
+                );
+            }
+            rmp_serde::from_slice(&data).context("Failed to deserialize content")
+        }
+        _ => serde_json::from_slice(plaintext).context("Failed to deserialize content"),
+    }
+}
+
+// Everything a relay could change about a message without touching the ciphertext.
+// The seq is left out, it is numbered again on every hop.
+fn associated_data(message: &ClipboardMessage, room: Option<&str>) -> Result<Vec<u8>> {
+    serde_json::to_vec(&(&message.client_id, message.selection, message.clock, room))
+        .context("Failed to serialize associated data")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::sync::{FileEntry, Selection};
+    use std::sync::OnceLock;
+
+    // Key derivation is slow in debug builds, so it is done once
+    fn cipher() -> &'static ContentCipher {
+        static CIPHER: OnceLock<ContentCipher> = OnceLock::new();
+        CIPHER.get_or_init(|| ContentCipher::from_passphrase("correct horse").unwrap())
+    }
+
+    fn message(text: &str) -> ClipboardMessage {
+        let mut message = ClipboardMessage::new(
+            ClipboardContent::Text(text.to_string()),
+            Some("alice".to_string()),
+        );
+        message.clock = Some(42);
+        message
+    }
+
+    fn sealed(room: Option<&str>) -> ClipboardMessage {
+        let mut message = message("secret");
+        cipher().seal(&mut message, room).unwrap();
+        message
+    }
+
+    fn opens(mut message: ClipboardMessage, room: Option<&str>) -> bool {
+        cipher().open(&mut message, room).is_ok()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut message = sealed(None);
+        assert!(matches!(
+            message.content,
+            ClipboardContent::Encrypted { .. }
+        ));
+
+        cipher().open(&mut message, None).unwrap();
+        assert!(matches!(&message.content, ClipboardContent::Text(text) if text == "secret"));
+    }
+
+    #[test]
+    fn binary_content_is_not_base64_encoded() {
+        let data = vec![7u8; 3000];
+        let mut message = message("");
+        message.content = ClipboardContent::Files(vec![FileEntry {
+            name: "blob".to_string(),
+            data: data.clone(),
+        }]);
+        cipher().seal(&mut message, None).unwrap();
+        let ClipboardContent::Encrypted { data: sealed, .. } = &message.content else {
+            panic!("content not sealed");
+        };
+        // Base64 would take a third more than the data itself
+        assert!(sealed.len() < data.len() + 100);
+
+        cipher().open(&mut message, None).unwrap();
+        assert!(
+            matches!(&message.content, ClipboardContent::Files(files) if files[0].data == data)
+        );
+    }
+
+    fn sealed_len(content: ClipboardContent) -> usize {
+        let mut message = message("");
+        message.content = content;
+        cipher().seal(&mut message, None).unwrap();
+        let ClipboardContent::Encrypted { data, .. } = &message.content else {
+            panic!("content not sealed");
+        };
+        let len = data.len();
+        cipher().open(&mut message, None).unwrap();
+        len
+    }
+
+    #[test]
+    fn text_is_compressed_before_sealing() {
+        let text = "lorem ipsum dolor sit amet ".repeat(1000);
+        assert!(sealed_len(ClipboardContent::Text(text.clone())) < text.len() / 10);
+    }
+
+    #[test]
+    fn content_sealed_by_older_clients_opens() {
+        let mut message = message("");
+        let aad = associated_data(&message, None).unwrap();
+        let json = serde_json::to_vec(&ClipboardContent::Text("legacy".to_string())).unwrap();
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let data = cipher()
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &json,
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        message.content = ClipboardContent::Encrypted {
+            nonce: nonce.to_vec(),
+            data,
+        };
+
+        cipher().open(&mut message, None).unwrap();
+        assert!(matches!(&message.content, ClipboardContent::Text(text) if text == "legacy"));
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let cipher = ContentCipher::from_passphrase("battery staple").unwrap();
+        assert!(cipher.open(&mut sealed(None), None).is_err());
+    }
+
+    #[test]
+    fn plaintext_is_rejected() {
+        assert!(!opens(message("injected"), None));
+    }
+
+    #[test]
+    fn retagged_messages_are_rejected() {
+        let mut other_device = sealed(None);
+        other_device.client_id = Some("mallory".to_string());
+        assert!(!opens(other_device, None));
+
+        let mut other_selection = sealed(None);
+        other_selection.selection = Selection::Primary;
+        assert!(!opens(other_selection, None));
+
+        // A replay can't be made to look newer than what was seen since
+        let mut newer = sealed(None);
+        newer.clock = Some(43);
+        assert!(!opens(newer, None));
+
+        assert!(!opens(sealed(Some("work")), Some("home")));
+        assert!(!opens(sealed(Some("work")), None));
+        assert!(opens(sealed(Some("work")), Some("work")));
+    }
+
+    #[test]
+    fn sequence_numbers_may_change() {
+        // Each hop numbers messages again
+        let mut message = sealed(None);
+        message.seq = Some(7);
+        assert!(opens(message, None));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::modules::protocol::PROTOCOL_VERSION;
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+pub const SERVICE_TYPE: &str = "_copi._tcp.local.";
+pub const DEFAULT_GROUP: &str = "default";
+
+// How long a client browses before giving up (it retries on the next reconnect)
+const BROWSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps a server announced on the local network until dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+// mDNS names only allow letters, digits and hyphens
+fn local_host_name() -> String {
+    let host = gethostname::gethostname().to_string_lossy().into_owned();
+    let host = host.strip_suffix(".local").unwrap_or(&host);
+    let host: String = host
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    if host.is_empty() {
+        "copi".to_string()
+    } else {
+        host
+    }
+}
+
+/// Announce a copi server listening on `port` as `_copi._tcp`.
+pub fn advertise(port: u16, group: &str, tls: bool) -> Result<Advertisement> {
+    let daemon = ServiceDaemon::new().context("Failed to start mDNS daemon")?;
+
+    let host = local_host_name();
+    let properties = HashMap::from([
+        ("group".to_string(), group.to_string()),
+        ("version".to_string(), PROTOCOL_VERSION.to_string()),
+        ("tls".to_string(), if tls { "1" } else { "0" }.to_string()),
+    ]);
+    // Instance names have to be unique on the network
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        &format!("{}-{}", host, port),
+        &format!("{}.local.", host),
+        "",
+        port,
+        properties,
+    )?
+    .enable_addr_auto();
+
+    let fullname = info.get_fullname().to_string();
+    daemon.register(info)?;
+    Ok(Advertisement { daemon, fullname })
+}
+
+/// A server found on the local network
+#[derive(Debug, Clone, Copy)]
+pub struct DiscoveredServer {
+    pub addr: SocketAddr,
+    // Whether it announced TLS; its certificate still has to be trusted some other way
+    pub tls: bool,
+}
+
+/// Browse the local network for a copi server of `group`.
+pub async fn discover(group: &str) -> Result<DiscoveredServer> {
+    let daemon = ServiceDaemon::new().context("Failed to start mDNS daemon")?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+
+    let found = tokio::time::timeout(BROWSE_TIMEOUT, async {
+        while let Ok(event) = receiver.recv_async().await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+            if info.get_property_val_str("group") != Some(group) {
+                continue;
+            }
+            // Prefer IPv4, link-local IPv6 addresses are unusable without a scope id
+            let ip = info
+                .get_addresses()
+                .iter()
+                .filter(|ip| match ip {
+                    IpAddr::V4(_) => true,
+                    IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) != 0xfe80,
+                })
+                .min_by_key(|ip| ip.is_ipv6())
+                .copied();
+            if let Some(ip) = ip {
+                let tls = info.get_property_val_str("tls") == Some("1");
+                println!(
+                    "Discovered server {} at {}{}",
+                    info.get_fullname(),
+                    SocketAddr::new(ip, info.get_port()),
+                    if tls { " (TLS)" } else { "" }
+                );
+                return Some(DiscoveredServer {
+                    addr: SocketAddr::new(ip, info.get_port()),
+                    tls,
+                });
+            }
+        }
+        None
+    })
+    .await;
+
+    let _ = daemon.shutdown();
+    match found {
+        Ok(Some(server)) => Ok(server),
+        _ => anyhow::bail!(
+            "No copi server found on the local network for group '{}'",
+            group
+        ),
+    }
+}
@@ -0,0 +1,452 @@
+use crate::modules::sync::{ClipboardContent, HTML_MIME, TEXT_MIME};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryLimits {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub max_age: Duration,
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 1000,
+            max_bytes: 100 * 1024 * 1024,
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    // 产生这条内容的客户端，None 表示服务器本地剪贴板
+    #[serde(default)]
+    pub client_id: Option<String>,
+    // 设备名称，旧版本记录的条目没有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    pub content: ClipboardContent,
+}
+
+impl HistoryEntry {
+    /// Where the content was copied: device name, client id or "server"
+    pub fn origin(&self) -> &str {
+        self.device_name
+            .as_deref()
+            .or(self.client_id.as_deref())
+            .unwrap_or("server")
+    }
+
+    /// One line preview of text content
+    pub fn preview(&self, max_chars: usize) -> String {
+        let text: Cow<str> = match &self.content {
+            ClipboardContent::Text(text) => text.into(),
+            ClipboardContent::Html { html, text } => {
+                if text.is_empty() {
+                    html.into()
+                } else {
+                    text.into()
+                }
+            }
+            ClipboardContent::Multi(_) => match [TEXT_MIME, HTML_MIME]
+                .iter()
+                .find_map(|mime| self.content.representation(mime))
+            {
+                Some(text) => String::from_utf8_lossy(text),
+                None => return String::new(),
+            },
+            _ => return String::new(),
+        };
+        let line = text.lines().next().unwrap_or_default();
+        let mut preview: String = line.chars().take(max_chars).collect();
+        if preview.len() < text.len() {
+            preview.push('…');
+        }
+        preview
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        match &self.content {
+            ClipboardContent::Text(text) => text.to_lowercase().contains(&query),
+            ClipboardContent::Html { html, text } => {
+                text.to_lowercase().contains(&query) || html.to_lowercase().contains(&query)
+            }
+            ClipboardContent::Multi(_) => [TEXT_MIME, HTML_MIME]
+                .iter()
+                .filter_map(|mime| self.content.representation(mime))
+                .any(|text| {
+                    String::from_utf8_lossy(text)
+                        .to_lowercase()
+                        .contains(&query)
+                }),
+            _ => false,
+        }
+    }
+}
+
+/// Clipboard history stored as JSON Lines, oldest entry first.
+///
+/// New entries are appended; the file is only rewritten when limits are exceeded.
+/// Changes made by other processes (e.g. `copi history clear`) are picked up before
+/// writing, so they aren't undone.
+pub struct HistoryStore {
+    path: PathBuf,
+    limits: HistoryLimits,
+    // Entry together with its serialized size
+    entries: Vec<(HistoryEntry, usize)>,
+    // Size and modification time of the file when we last read or wrote it
+    synced: Option<FileState>,
+}
+
+type FileState = (u64, Option<SystemTime>);
+
+impl HistoryStore {
+    pub fn default_path() -> Result<PathBuf> {
+        let dir = dirs::data_local_dir()
+            .ok_or_else(|| anyhow::anyhow!("Cannot determine data directory"))?;
+        Ok(dir.join("copi").join("history.jsonl"))
+    }
+
+    pub fn open(path: &Path, limits: HistoryLimits) -> Result<Self> {
+        let mut store = Self {
+            path: path.to_path_buf(),
+            limits,
+            entries: Vec::new(),
+            synced: None,
+        };
+        store.reload()?;
+        if store.prune() {
+            store.rewrite()?;
+        }
+        Ok(store)
+    }
+
+    // Read the entries from the file again
+    fn reload(&mut self) -> Result<()> {
+        let path = &self.path;
+        let mut entries = Vec::new();
+
+        if path.exists() {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to open history {}", path.display()))?;
+            // 旧版本创建的历史文件其他用户可读
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                if file.metadata()?.permissions().mode() & 0o077 != 0 {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                }
+            }
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // 跳过损坏的行（例如写入中途被中断）
+                match serde_json::from_str::<HistoryEntry>(&line) {
+                    Ok(entry) => entries.push((entry, line.len() + 1)),
+                    Err(e) => eprintln!("Skipping invalid history entry: {}", e),
+                }
+            }
+        }
+
+        self.entries = entries;
+        self.synced = self.file_state();
+        Ok(())
+    }
+
+    fn file_state(&self) -> Option<FileState> {
+        let metadata = std::fs::metadata(&self.path).ok()?;
+        Some((metadata.len(), metadata.modified().ok()))
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+        self.entries.iter().map(|(entry, _)| entry)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries().find(|entry| entry.id == id)
+    }
+
+    pub fn search<'a>(
+        &'a self,
+        query: &'a str,
+    ) -> impl DoubleEndedIterator<Item = &'a HistoryEntry> {
+        self.entries().filter(move |entry| entry.matches(query))
+    }
+
+    /// Append content to the history, skipping it if it equals the latest entry.
+    pub fn record(
+        &mut self,
+        content: &ClipboardContent,
+        client_id: Option<&str>,
+        device_name: Option<&str>,
+    ) -> Result<()> {
+        // 文件内容不记录，收到的文件保存在接收目录中
+        if matches!(
+            content,
+            ClipboardContent::Encrypted { .. } | ClipboardContent::Files(_)
+        ) {
+            return Ok(());
+        }
+
+        // 文件被其他进程改写过（例如 copi history clear），以文件为准，不把已删除的条目写回去
+        if self.file_state() != self.synced {
+            self.reload()?;
+        }
+
+        let serialized = serde_json::to_string(content)?;
+        if let Some((last, _)) = self.entries.last() {
+            if serde_json::to_string(&last.content)? == serialized {
+                return Ok(());
+            }
+        }
+
+        let entry = HistoryEntry {
+            id: self.entries.last().map_or(1, |(entry, _)| entry.id + 1),
+            timestamp: now(),
+            client_id: client_id.map(str::to_string),
+            device_name: device_name.map(str::to_string),
+            content: content.clone(),
+        };
+        let line = serde_json::to_string(&entry)?;
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = private_options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open history {}", self.path.display()))?;
+        writeln!(file, "{}", line)?;
+        self.entries.push((entry, line.len() + 1));
+        self.synced = self.file_state();
+
+        if self.prune() {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.entries.clear();
+        self.rewrite()
+    }
+
+    // Drop the oldest entries until all limits hold, returns whether anything was removed
+    fn prune(&mut self) -> bool {
+        let before = self.entries.len();
+        let oldest_allowed = now().saturating_sub(self.limits.max_age.as_secs());
+        self.entries
+            .retain(|(entry, _)| entry.timestamp >= oldest_allowed);
+
+        let mut total: usize = self.entries.iter().map(|(_, size)| size).sum();
+        let mut excess = self.entries.len().saturating_sub(self.limits.max_entries);
+        let mut drop = 0;
+        for (_, size) in &self.entries {
+            if excess == 0 && total <= self.limits.max_bytes {
+                break;
+            }
+            excess = excess.saturating_sub(1);
+            total -= size;
+            drop += 1;
+        }
+        self.entries.drain(..drop);
+
+        self.entries.len() != before
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // 先写临时文件再重命名，避免中途失败丢失全部历史
+        let tmp = self.path.with_extension("jsonl.tmp");
+        // 中断后残留的临时文件可能权限过宽，重新创建
+        let _ = std::fs::remove_file(&tmp);
+        let mut file = private_options().write(true).create_new(true).open(&tmp)?;
+        for (entry, _) in &self.entries {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.synced = self.file_state();
+        Ok(())
+    }
+}
+
+// 历史中可能有密码等敏感内容，新建的文件只有自己能读写
+fn private_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Human readable age of a timestamp, e.g. "5m ago"
+pub fn format_age(timestamp: u64) -> String {
+    let secs = now().saturating_sub(timestamp);
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("copi-test-{:x}", rand::random::<u64>()))
+            .join("history.jsonl")
+    }
+
+    fn text(text: &str) -> ClipboardContent {
+        ClipboardContent::Text(text.to_string())
+    }
+
+    fn texts(store: &HistoryStore) -> Vec<String> {
+        store.entries().map(|entry| entry.preview(100)).collect()
+    }
+
+    fn open(path: &Path, limits: HistoryLimits) -> HistoryStore {
+        HistoryStore::open(path, limits).unwrap()
+    }
+
+    #[test]
+    fn entries_survive_reopening() {
+        let path = temp_path();
+        let mut store = open(&path, HistoryLimits::default());
+        for copied in ["one", "two", "two", "three"] {
+            store.record(&text(copied), Some("laptop"), None).unwrap();
+        }
+        // Copying the same thing twice is one entry
+        assert_eq!(texts(&store), ["one", "two", "three"]);
+
+        let store = open(&path, HistoryLimits::default());
+        assert_eq!(texts(&store), ["one", "two", "three"]);
+        assert_eq!(store.get(3).unwrap().origin(), "laptop");
+    }
+
+    #[test]
+    fn oldest_entries_are_pruned_over_the_entry_limit() {
+        let path = temp_path();
+        let limits = HistoryLimits {
+            max_entries: 2,
+            ..HistoryLimits::default()
+        };
+        let mut store = open(&path, limits);
+        for copied in ["one", "two", "three"] {
+            store.record(&text(copied), None, None).unwrap();
+        }
+        assert_eq!(texts(&store), ["two", "three"]);
+        assert_eq!(texts(&open(&path, limits)), ["two", "three"]);
+    }
+
+    #[test]
+    fn oldest_entries_are_pruned_over_the_size_limit() {
+        let path = temp_path();
+        let mut store = open(&path, HistoryLimits::default());
+        for copied in ["one", "two", "three"] {
+            store
+                .record(&text(&copied.repeat(100)), None, None)
+                .unwrap();
+        }
+
+        // Room for two of them
+        let size = std::fs::metadata(&path).unwrap().len() as usize;
+        let limits = HistoryLimits {
+            max_bytes: size * 3 / 4,
+            ..HistoryLimits::default()
+        };
+        let store = open(&path, limits);
+        assert_eq!(store.entries().count(), 2);
+        assert!(store.get(1).is_none());
+        assert!(std::fs::metadata(&path).unwrap().len() as usize <= limits.max_bytes);
+    }
+
+    #[test]
+    fn expired_entries_are_pruned() {
+        let path = temp_path();
+        let old = HistoryEntry {
+            id: 1,
+            timestamp: now() - 3600,
+            client_id: None,
+            device_name: None,
+            content: text("old"),
+        };
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let line = serde_json::to_string(&old).unwrap();
+        std::fs::write(&path, format!("{}\nnot json\n", line)).unwrap();
+
+        let store = open(&path, HistoryLimits::default());
+        assert_eq!(texts(&store), ["old"]);
+
+        let limits = HistoryLimits {
+            max_age: Duration::from_secs(60),
+            ..HistoryLimits::default()
+        };
+        let mut store = open(&path, limits);
+        assert_eq!(store.entries().count(), 0);
+        store.record(&text("new"), None, None).unwrap();
+        assert_eq!(texts(&store), ["new"]);
+    }
+
+    #[test]
+    fn cleared_history_is_not_written_back() {
+        let path = temp_path();
+        let mut daemon = open(&path, HistoryLimits::default());
+        daemon.record(&text("secret"), None, None).unwrap();
+
+        // `copi history clear` runs in another process
+        open(&path, HistoryLimits::default()).clear().unwrap();
+        daemon.record(&text("after"), None, None).unwrap();
+
+        assert_eq!(texts(&daemon), ["after"]);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("secret"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn history_is_readable_by_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let path = temp_path();
+        let mut store = open(&path, HistoryLimits::default());
+        store.record(&text("one"), None, None).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        store.clear().unwrap();
+        assert_eq!(mode(&path), 0o600);
+
+        // Files created by earlier versions are tightened
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        open(&path, HistoryLimits::default());
+        assert_eq!(mode(&path), 0o600);
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+/// Who this copi instance is: a device id that survives restarts and a name for humans.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub device_id: String,
+    pub name: String,
+}
+
+impl Identity {
+    /// Load the device id (generating it on first run) and pick the device name,
+    /// defaulting to the host name
+    pub fn load(name: Option<String>) -> Result<Self> {
+        let path = Self::device_id_path()?;
+        let device_id = match std::fs::read_to_string(&path) {
+            Ok(id) if !id.trim().is_empty() => id.trim().to_string(),
+            _ => {
+                let id: String = rand::random::<[u8; 16]>()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, &id)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                id
+            }
+        };
+
+        let name = name.unwrap_or_else(host_name);
+        Ok(Self { device_id, name })
+    }
+
+    // Linux has a state directory, other platforms keep it with the data
+    fn device_id_path() -> Result<PathBuf> {
+        let dir = dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .ok_or_else(|| anyhow::anyhow!("Cannot determine state directory"))?;
+        Ok(dir.join("copi").join("device-id"))
+    }
+}
+
+fn host_name() -> String {
+    let host = gethostname::gethostname().to_string_lossy().into_owned();
+    match host.strip_suffix(".local") {
+        Some(host) => host.to_string(),
+        None => host,
+    }
+}
@@ -0,0 +1,105 @@
+use crate::modules::sync::ClipboardMessage;
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+
+// Enough to cover every message still circulating in a small mesh
+const SEEN_CAPACITY: usize = 1024;
+
+/// Messages a peer has already applied, so that copies arriving over other paths
+/// of the mesh are dropped instead of being applied and forwarded again.
+pub struct SeenMessages {
+    origin: String,
+    keys: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenMessages {
+    /// `origin` is the client id this peer stamps on its own messages
+    pub fn new(origin: String) -> Self {
+        Self {
+            origin,
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remember the message, returns false if it is our own or was already seen
+    pub fn insert(&mut self, message: &ClipboardMessage) -> bool {
+        if message.client_id.as_deref() == Some(self.origin.as_str()) {
+            return false;
+        }
+
+        let key = Self::key(message);
+        if !self.keys.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    // Forwarding keeps origin, timestamp and clock; content is compared after decryption
+    fn key(message: &ClipboardMessage) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(message.client_id.as_deref().unwrap_or_default().as_bytes());
+        hasher.update(b":");
+        hasher.update(message.timestamp.to_le_bytes());
+        hasher.update(message.clock.unwrap_or_default().to_le_bytes());
+        hasher.update(serde_json::to_vec(&message.content).unwrap_or_default());
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::sync::ClipboardContent;
+
+    fn message(text: &str, origin: &str) -> ClipboardMessage {
+        ClipboardMessage::new(
+            ClipboardContent::Text(text.to_string()),
+            Some(origin.into()),
+        )
+    }
+
+    #[test]
+    fn own_messages_coming_back_are_dropped() {
+        let mut seen = SeenMessages::new("me".into());
+        assert!(!seen.insert(&message("mine", "me")));
+    }
+
+    #[test]
+    fn copies_arriving_over_another_path_are_dropped() {
+        let mut seen = SeenMessages::new("me".into());
+        let original = message("hello", "peer");
+        assert!(seen.insert(&original));
+
+        // Relayed by another peer, which tags it with its own connection and device name
+        let mut relayed = original.clone().with_device_name("relay");
+        relayed.source = Some(([127, 0, 0, 1], 9000).into());
+        relayed.seq = Some(7);
+        assert!(!seen.insert(&relayed));
+
+        // The same text copied again later is a new message
+        let mut again = original.clone();
+        again.clock = Some(original.clock.unwrap_or_default() + 1);
+        assert!(seen.insert(&again));
+        assert!(seen.insert(&message("hello", "other peer")));
+    }
+
+    #[test]
+    fn oldest_messages_are_forgotten() {
+        let mut seen = SeenMessages::new("me".into());
+        let first = message("0", "peer");
+        assert!(seen.insert(&first));
+        for index in 1..=SEEN_CAPACITY {
+            assert!(seen.insert(&message(&index.to_string(), "peer")));
+        }
+        assert_eq!(seen.keys.len(), SEEN_CAPACITY);
+        assert!(seen.insert(&first));
+    }
+}
@@ -1,2 +1,14 @@
+pub mod auth;
 pub mod clipboard;
+pub mod clock;
+pub mod config;
+pub mod crypto;
+pub mod discovery;
+pub mod history;
+pub mod identity;
+pub mod mesh;
+pub mod protocol;
 pub mod sync;
+pub mod tls;
+pub mod watcher;
+pub mod websocket;
@@ -0,0 +1,352 @@
+use crate::modules::identity::Identity;
+use crate::modules::sync::{ClipboardContent, ClipboardMessage};
+use serde::{Deserialize, Serialize};
+
+// Bump when the meaning of existing frames changes. Peers that never send a Hello
+// (copi <= 0.2) are treated as version 0.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Text,
+    Image,
+    Html,
+    Encrypted,
+    Files,
+    Multi,
+    // Kinds added by newer peers, so their Hello still deserializes
+    #[serde(other)]
+    Unknown,
+}
+
+/// Serialization of frames after the handshake, listed in order of preference
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    // Binary fields travel as raw bytes instead of base64
+    MessagePack,
+    Json,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    #[serde(other)]
+    Unknown,
+}
+
+/// First frame sent by both sides after authentication.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub version: u32,
+    pub content_kinds: Vec<ContentKind>,
+    pub encodings: Vec<Encoding>,
+    // Added after v1 shipped, older peers don't send it
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+    // Whether the peer reassembles messages sent as Chunk frames
+    #[serde(default)]
+    pub chunked: bool,
+    // Whether the peer answers Ping frames
+    #[serde(default)]
+    pub heartbeat: bool,
+    // Whether the server acknowledges clipboard messages that carry a sequence number
+    #[serde(default)]
+    pub acks: bool,
+    // Whether the peer tells PRIMARY selection messages apart from clipboard ones
+    #[serde(default)]
+    pub primary: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    // Room the client wants to join; the server echoes it back once the client is in
+    // it, so a server without rooms can't silently put the client in the shared one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    // Set by the server when the room has its own token and a challenge follows
+    #[serde(default)]
+    pub room_auth: bool,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            content_kinds: vec![
+                ContentKind::Text,
+                ContentKind::Image,
+                ContentKind::Html,
+                ContentKind::Encrypted,
+                ContentKind::Files,
+                ContentKind::Multi,
+            ],
+            encodings: vec![Encoding::MessagePack, Encoding::Json],
+            compression: vec![Compression::Zstd],
+            chunked: true,
+            heartbeat: true,
+            acks: true,
+            primary: true,
+            device_id: None,
+            device_name: None,
+            room: None,
+            room_auth: false,
+        }
+    }
+
+    /// Introduce ourselves as `identity` to the peer
+    pub fn with_identity(mut self, identity: &Identity) -> Self {
+        self.device_id = Some(identity.device_id.clone());
+        self.device_name = Some(identity.name.clone());
+        self
+    }
+
+    /// Ask the server to put us in `room` instead of the shared one
+    pub fn with_room(mut self, room: Option<String>) -> Self {
+        self.room = room;
+        self
+    }
+
+    /// Capabilities of peers that predate the handshake
+    pub fn legacy() -> Self {
+        Self {
+            version: 0,
+            content_kinds: vec![ContentKind::Text, ContentKind::Image],
+            encodings: vec![Encoding::Json],
+            compression: Vec::new(),
+            chunked: false,
+            heartbeat: false,
+            acks: false,
+            primary: false,
+            device_id: None,
+            device_name: None,
+            room: None,
+            room_auth: false,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let kinds = self
+            .content_kinds
+            .iter()
+            .map(|kind| format!("{:?}", kind))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let description = match &self.device_name {
+            Some(name) => format!("{}, protocol v{}, content: {}", name, self.version, kinds),
+            None => format!("protocol v{}, content: {}", self.version, kinds),
+        };
+        match &self.room {
+            Some(room) => format!("{}, room: {}", description, room),
+            None => description,
+        }
+    }
+
+    /// Encoding used after the handshake: the client's most preferred one that the
+    /// server supports. Both sides compute the same result from the two hellos.
+    pub fn negotiate_encoding(client: &Hello, server: &Hello) -> Encoding {
+        client
+            .encodings
+            .iter()
+            .copied()
+            .find(|encoding| *encoding != Encoding::Unknown && server.encodings.contains(encoding))
+            .unwrap_or(Encoding::Json)
+    }
+
+    /// Compression used after the handshake, chosen the same way as the encoding
+    pub fn negotiate_compression(client: &Hello, server: &Hello) -> Option<Compression> {
+        client.compression.iter().copied().find(|compression| {
+            *compression != Compression::Unknown && server.compression.contains(compression)
+        })
+    }
+}
+
+/// Control frames share the connection with clipboard messages once both sides
+/// have exchanged a Hello. Legacy peers only ever see plain `ClipboardMessage`s.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Control {
+    Hello(Hello),
+    Chunk(Chunk),
+    // The sender gave up on a transfer, e.g. because newer content superseded it
+    Cancel { transfer_id: u64 },
+    // Sent periodically by both sides, answered with a Pong
+    Ping,
+    Pong,
+    // The server received the client's message with this sequence number
+    Ack { seq: u64 },
+}
+
+/// Piece of a serialized `ClipboardMessage` too large for a single frame.
+///
+/// Chunks of a transfer are sent in order on one connection, starting at index 0.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    pub transfer_id: u64,
+    pub index: u32,
+    pub total: u32,
+    // Size of the whole serialized message
+    pub size: u64,
+    #[serde(with = "crate::modules::sync::payload")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Frame {
+    Control(Control),
+    Clipboard(ClipboardMessage),
+}
+
+impl ClipboardContent {
+    pub fn kind(&self) -> ContentKind {
+        match self {
+            ClipboardContent::Text(_) => ContentKind::Text,
+            ClipboardContent::Image { .. } => ContentKind::Image,
+            ClipboardContent::Html { .. } => ContentKind::Html,
+            ClipboardContent::Encrypted { .. } => ContentKind::Encrypted,
+            ClipboardContent::Files(_) => ContentKind::Files,
+            ClipboardContent::Multi(_) => ContentKind::Multi,
+        }
+    }
+
+    /// Convert the content into one of `kinds` (what a peer or backend understands).
+    ///
+    /// Returns `None` when there is no sensible fallback and the content should be
+    /// skipped.
+    pub fn downgrade_for(&self, kinds: &[ContentKind]) -> Option<ClipboardContent> {
+        if kinds.contains(&self.kind()) {
+            return Some(self.clone());
+        }
+
+        match self {
+            ClipboardContent::Html { html, text } if kinds.contains(&ContentKind::Text) => {
+                let text = if text.is_empty() { html } else { text };
+                Some(ClipboardContent::Text(text.clone()))
+            }
+            // Only one format fits, keep the one that carries most of the copy
+            ClipboardContent::Multi(_) => self
+                .alternatives()
+                .iter()
+                .find_map(|alternative| alternative.downgrade_for(kinds)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::sync::{FileEntry, Representation, HTML_MIME, TEXT_MIME};
+
+    fn html() -> ClipboardContent {
+        ClipboardContent::Html {
+            html: "<b>bold</b>".to_string(),
+            text: "bold".to_string(),
+        }
+    }
+
+    #[test]
+    fn current_peers_use_messagepack_and_zstd() {
+        let (client, server) = (Hello::current(), Hello::current());
+        assert_eq!(
+            Hello::negotiate_encoding(&client, &server),
+            Encoding::MessagePack
+        );
+        assert_eq!(
+            Hello::negotiate_compression(&client, &server),
+            Some(Compression::Zstd)
+        );
+    }
+
+    #[test]
+    fn legacy_peers_stay_on_json() {
+        let (current, legacy) = (Hello::current(), Hello::legacy());
+        assert_eq!(Hello::negotiate_encoding(&current, &legacy), Encoding::Json);
+        assert_eq!(Hello::negotiate_encoding(&legacy, &current), Encoding::Json);
+        assert_eq!(Hello::negotiate_compression(&current, &legacy), None);
+    }
+
+    #[test]
+    fn hello_from_newer_peer_is_understood() {
+        let hello: Hello = serde_json::from_str(
+            r#"{"version":2,"content_kinds":["Text","Video"],"encodings":["Cbor","Json"],
+                "compression":["Brotli"],"chunked":true,"future_flag":1}"#,
+        )
+        .unwrap_or_else(|e| panic!("newer hello rejected: {}", e));
+        assert_eq!(
+            hello.content_kinds,
+            vec![ContentKind::Text, ContentKind::Unknown]
+        );
+        // Nothing we don't know is ever picked
+        assert_eq!(
+            Hello::negotiate_encoding(&hello, &Hello::current()),
+            Encoding::Json
+        );
+        assert_eq!(
+            Hello::negotiate_compression(&hello, &Hello::current()),
+            None
+        );
+    }
+
+    #[test]
+    fn hello_from_first_protocol_version_gets_defaults() {
+        let hello: Hello = serde_json::from_str(
+            r#"{"version":1,"content_kinds":["Text","Image","Html"],"encodings":["Json"]}"#,
+        )
+        .unwrap();
+        assert!(hello.compression.is_empty());
+        assert!(!hello.chunked && !hello.heartbeat && !hello.acks && !hello.primary);
+        assert!(hello.room.is_none());
+    }
+
+    #[test]
+    fn legacy_messages_are_clipboard_frames() {
+        let json = r#"{"content":{"Text":"hi"},"timestamp":1,"client_id":"old"}"#;
+        let frame: Frame = serde_json::from_str(json).unwrap();
+        assert!(
+            matches!(frame, Frame::Clipboard(message) if message.client_id.as_deref() == Some("old"))
+        );
+    }
+
+    #[test]
+    fn supported_content_is_sent_unchanged() {
+        let downgraded = html().downgrade_for(&Hello::current().content_kinds);
+        assert!(matches!(downgraded, Some(ClipboardContent::Html { .. })));
+    }
+
+    #[test]
+    fn html_is_downgraded_to_text() {
+        let downgraded = html().downgrade_for(&Hello::legacy().content_kinds);
+        assert!(matches!(downgraded, Some(ClipboardContent::Text(text)) if text == "bold"));
+    }
+
+    #[test]
+    fn unsupported_content_is_skipped() {
+        let files = ClipboardContent::Files(vec![FileEntry {
+            name: "notes.txt".to_string(),
+            data: b"notes".to_vec(),
+        }]);
+        assert!(files
+            .downgrade_for(&Hello::legacy().content_kinds)
+            .is_none());
+    }
+
+    #[test]
+    fn multi_format_copies_keep_the_richest_supported_format() {
+        let multi = ClipboardContent::Multi(vec![
+            Representation::new(HTML_MIME, b"<i>hi</i>".to_vec()),
+            Representation::new("text/rtf", b"{\\rtf hi}".to_vec()),
+            Representation::new(TEXT_MIME, b"hi".to_vec()),
+        ]);
+        let html_only = [ContentKind::Html, ContentKind::Text];
+        assert!(matches!(
+            multi.downgrade_for(&html_only),
+            Some(ClipboardContent::Html { .. })
+        ));
+        assert!(matches!(
+            multi.downgrade_for(&Hello::legacy().content_kinds),
+            Some(ClipboardContent::Text(text)) if text == "hi"
+        ));
+    }
+}
@@ -1,17 +1,36 @@
+use crate::modules::auth::AuthToken;
+use crate::modules::clock::Version;
+use crate::modules::crypto::ContentCipher;
+use crate::modules::discovery;
+use crate::modules::identity::Identity;
+use crate::modules::protocol::{Chunk, Compression, ContentKind, Control, Encoding, Frame, Hello};
+use crate::modules::websocket::{self, WebSocketUrl};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io::Cursor;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{broadcast, mpsc};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClipboardContent {
     Text(String),
     Image {
-        // PNG format, base64 encoded
-        data: String,
+        // PNG format
+        #[serde(with = "payload")]
+        data: Vec<u8>,
         width: u32,
         height: u32,
     },
@@ -22,6 +41,281 @@ pub enum ClipboardContent {
         #[serde(default)]
         text: String,
     },
+    Encrypted {
+        // Serialized ClipboardContent, sealed with XChaCha20-Poly1305
+        #[serde(with = "payload")]
+        nonce: Vec<u8>,
+        #[serde(with = "payload")]
+        data: Vec<u8>,
+    },
+    // Files copied in a file manager, with their contents
+    Files(Vec<FileEntry>),
+    // One copy offered in several formats at once, e.g. text/html, text/plain and
+    // image/png from a browser. Sorted by MIME type.
+    Multi(Vec<Representation>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileEntry {
+    // File name without directories
+    pub name: String,
+    #[serde(with = "payload")]
+    pub data: Vec<u8>,
+}
+
+pub const TEXT_MIME: &str = "text/plain;charset=utf-8";
+pub const HTML_MIME: &str = "text/html";
+pub const PNG_MIME: &str = "image/png";
+// Files start with this whatever their name
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Representation {
+    pub mime: String,
+    #[serde(with = "payload")]
+    pub data: Vec<u8>,
+}
+
+// Binary fields are base64 strings in JSON (as older peers expect) and raw bytes in
+// binary encodings
+pub(crate) mod payload {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    // Accept either form: untagged enums buffer the input and no longer report
+    // whether the format is human readable
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(PayloadVisitor)
+    }
+
+    struct PayloadVisitor;
+
+    impl<'de> Visitor<'de> for PayloadVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("base64 string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            STANDARD.decode(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+impl ClipboardContent {
+    /// Short human readable description used in log lines
+    pub fn summary(&self) -> String {
+        match self {
+            ClipboardContent::Text(text) => format!("text ({} bytes)", text.len()),
+            ClipboardContent::Image { width, height, .. } => {
+                format!("image ({}x{})", width, height)
+            }
+            ClipboardContent::Html { html, .. } => format!("html ({} bytes)", html.len()),
+            ClipboardContent::Encrypted { data, .. } => {
+                format!("encrypted ({} bytes)", data.len())
+            }
+            ClipboardContent::Files(files) => {
+                let size: usize = files.iter().map(|file| file.data.len()).sum();
+                format!("{} file(s) ({} bytes)", files.len(), size)
+            }
+            ClipboardContent::Multi(formats) => {
+                let mimes: Vec<&str> = formats.iter().map(|format| format.mime.as_str()).collect();
+                format!("{} formats ({})", formats.len(), mimes.join(", "))
+            }
+        }
+    }
+
+    /// Whether zstd is worth running on this content. PNG data and ciphertext don't get
+    /// any smaller, so files and formats that are mostly PNG are sent as they are.
+    pub fn compressible(&self) -> bool {
+        match self {
+            ClipboardContent::Text(_) | ClipboardContent::Html { .. } => true,
+            ClipboardContent::Image { .. } | ClipboardContent::Encrypted { .. } => false,
+            ClipboardContent::Files(files) => !mostly_png(
+                files
+                    .iter()
+                    .map(|file| (file.data.starts_with(PNG_SIGNATURE), file.data.len())),
+            ),
+            ClipboardContent::Multi(formats) => !mostly_png(
+                formats
+                    .iter()
+                    .map(|format| (format.mime == PNG_MIME, format.data.len())),
+            ),
+        }
+    }
+
+    /// Combine the formats of one copy into content.
+    ///
+    /// Plain text, a PNG image or HTML with its text fallback become the matching
+    /// single-format content, so peers and history entries see what they always did.
+    pub fn from_representations(mut formats: Vec<Representation>) -> Option<ClipboardContent> {
+        formats.sort_by(|a, b| a.mime.cmp(&b.mime));
+        formats.dedup_by(|a, b| a.mime == b.mime);
+        let single = match formats.as_slice() {
+            [] => return None,
+            [_] => true,
+            [a, b] => a.kind() == Some(ContentKind::Html) && b.kind() == Some(ContentKind::Text),
+            _ => false,
+        };
+        let item = ClipboardContent::Multi(formats);
+        if single {
+            item.alternatives().into_iter().next()
+        } else {
+            Some(item)
+        }
+    }
+
+    /// Data of the `mime` format of a multi-format item
+    pub fn representation(&self, mime: &str) -> Option<&[u8]> {
+        let ClipboardContent::Multi(formats) = self else {
+            return None;
+        };
+        formats
+            .iter()
+            .find(|format| format.mime == mime)
+            .map(|format| format.data.as_slice())
+    }
+
+    /// The formats of a multi-format item as single-format content, the one that
+    /// keeps most of the copy first
+    pub fn alternatives(&self) -> Vec<ClipboardContent> {
+        let ClipboardContent::Multi(_) = self else {
+            return vec![self.clone()];
+        };
+        let text = self
+            .representation(TEXT_MIME)
+            .map(|data| String::from_utf8_lossy(data).into_owned());
+
+        let mut alternatives = Vec::new();
+        if let Some(data) = self.representation(PNG_MIME) {
+            // Only the PNG header is read for the size
+            let size = image::ImageReader::new(Cursor::new(data))
+                .with_guessed_format()
+                .ok()
+                .and_then(|reader| reader.into_dimensions().ok());
+            if let Some((width, height)) = size {
+                alternatives.push(ClipboardContent::Image {
+                    data: data.to_vec(),
+                    width,
+                    height,
+                });
+            }
+        }
+        if let Some(html) = self.representation(HTML_MIME) {
+            alternatives.push(ClipboardContent::Html {
+                html: String::from_utf8_lossy(html).into_owned(),
+                text: text.clone().unwrap_or_default(),
+            });
+        }
+        if let Some(text) = text {
+            alternatives.push(ClipboardContent::Text(text));
+        }
+        alternatives
+    }
+
+    /// Only the parts of the content whose kind passes `keep`.
+    ///
+    /// Formats of a multi-format item that don't map to a kind (e.g. browser metadata)
+    /// stay as long as any other format does.
+    pub fn retain_kinds(self, keep: impl Fn(ContentKind) -> bool) -> Option<ClipboardContent> {
+        let formats = match self {
+            ClipboardContent::Multi(formats) => formats,
+            other => return keep(other.kind()).then_some(other),
+        };
+        let formats: Vec<Representation> = formats
+            .into_iter()
+            .filter(|format| format.kind().is_none_or(&keep))
+            .collect();
+        if formats.iter().all(|format| format.kind().is_none()) {
+            return None;
+        }
+        ClipboardContent::from_representations(formats)
+    }
+}
+
+impl Representation {
+    pub fn new(mime: impl Into<String>, data: Vec<u8>) -> Self {
+        Self {
+            mime: mime.into(),
+            data,
+        }
+    }
+
+    /// Content kind of the format, None for formats only some applications understand
+    pub fn kind(&self) -> Option<ContentKind> {
+        match self.mime.as_str() {
+            TEXT_MIME => Some(ContentKind::Text),
+            HTML_MIME => Some(ContentKind::Html),
+            PNG_MIME => Some(ContentKind::Image),
+            "text/uri-list" => Some(ContentKind::Files),
+            _ => None,
+        }
+    }
+}
+
+// Whether more of the bytes in `parts` (is PNG, length) are PNG than not
+fn mostly_png(parts: impl Iterator<Item = (bool, usize)>) -> bool {
+    let (png, other) = parts.fold((0, 0), |(png, other), (is_png, len)| {
+        if is_png {
+            (png + len, other)
+        } else {
+            (png, other + len)
+        }
+    });
+    png > other
+}
+
+/// X11/Wayland selection a message was copied to. Other platforms only have the clipboard.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Selection {
+    /// CLIPBOARD, copied with Ctrl+C
+    #[default]
+    Clipboard,
+    /// PRIMARY, text selected with the mouse and pasted with a middle click
+    Primary,
+}
+
+impl Selection {
+    fn is_clipboard(&self) -> bool {
+        *self == Selection::Clipboard
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selection::Clipboard => f.write_str("clipboard"),
+            Selection::Primary => f.write_str("primary selection"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -30,22 +324,341 @@ pub struct ClipboardMessage {
     pub timestamp: u64,
     #[serde(default)]
     pub client_id: Option<String>,
+    // Hybrid logical clock stamp, None from versions that didn't order changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<u64>,
+    // Only sent to peers whose Hello says they understand PRIMARY
+    #[serde(default, skip_serializing_if = "Selection::is_clipboard")]
+    pub selection: Selection,
+    // Numbered by SyncClient when the server acknowledges messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    // (compressed, uncompressed) frame size, when the message arrived compressed
+    #[serde(skip)]
+    pub compressed: Option<(usize, usize)>,
+    // Name of the device the content was copied on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    // Client connection SyncServer received the message on, it is never sent back there
+    #[serde(skip)]
+    pub source: Option<SocketAddr>,
+    // Room of that connection, None for the shared one
+    #[serde(skip)]
+    pub room: Option<String>,
+}
+
+impl ClipboardMessage {
+    pub fn new(content: ClipboardContent, client_id: Option<String>) -> Self {
+        Self {
+            content,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            client_id,
+            clock: None,
+            selection: Selection::Clipboard,
+            seq: None,
+            device_name: None,
+            compressed: None,
+            source: None,
+            room: None,
+        }
+    }
+
+    /// Label the message with the name of the device it was copied on
+    pub fn with_device_name(mut self, name: &str) -> Self {
+        self.device_name = Some(name.to_string());
+        self
+    }
+
+    /// Mark the message as coming from `selection`
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Position in the order of clipboard changes, the client id being the origin
+    pub fn version(&self) -> Option<Version> {
+        self.clock.map(|clock| Version {
+            clock,
+            origin: self.client_id.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Who sent the message, for log lines
+    pub fn sender(&self) -> &str {
+        self.device_name
+            .as_deref()
+            .or(self.client_id.as_deref())
+            .unwrap_or("unknown")
+    }
+
+    /// Content summary for log lines, including the compression on the wire
+    pub fn summary(&self) -> String {
+        match self.compressed {
+            Some((compressed, uncompressed)) => format!(
+                "{}, compressed {} -> {} bytes",
+                self.content.summary(),
+                uncompressed,
+                compressed
+            ),
+            None => self.content.summary(),
+        }
+    }
+}
+
+// Exchanged before the first ClipboardMessage when a token is configured
+#[derive(Serialize, Deserialize, Debug)]
+enum AuthMessage {
+    Challenge { nonce: String },
+    Response { mac: String },
+    Accepted,
+    Rejected { reason: String },
+}
+
+// Sockets that don't finish the handshake are closed after this long
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// New clients send their Hello right away; silence means a legacy client.
+// Legacy clients only get their first message after this delay.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(3);
+// Messages buffered per room for clients that fall behind
+const ROOM_CAPACITY: usize = 100;
+// Named rooms that may exist at the same time, and the longest room name in bytes
+const MAX_ROOMS: usize = 1000;
+const MAX_ROOM_NAME: usize = 64;
+
+/// Ping interval, and how long a connection may stay silent before it is considered dead.
+///
+/// Every ping is answered with a pong, so a side hears from its peer at least once per
+/// interval, whatever the peer's own settings are.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+impl Heartbeat {
+    // Pings sent on a connection, the first one after a full interval
+    fn ticker(&self) -> tokio::time::Interval {
+        let mut ticker =
+            tokio::time::interval_at(tokio::time::Instant::now() + self.interval, self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker
+    }
+}
+
+// Resolves on the next ping, never without a heartbeat
+async fn next_ping(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// With a heartbeat, a peer that sends nothing at all for the timeout is gone. A large
+// frame may take longer than that, as long as its bytes keep coming.
+async fn read_frame_timeout<R>(
+    reader: &mut R,
+    codec: Codec,
+    heartbeat: Option<Heartbeat>,
+) -> Result<Frame, FrameError>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(heartbeat) = heartbeat else {
+        return read_frame(reader, codec).await;
+    };
+    let mut reader = IdleReader::new(reader, heartbeat.timeout);
+    let result = read_frame(&mut reader, codec).await;
+    if reader.timed_out {
+        return Err(FrameError::TimedOut(heartbeat.timeout));
+    }
+    result
+}
+
+// Fails reads once nothing has arrived for `timeout`, the deadline moves with every byte
+struct IdleReader<'a, R> {
+    inner: &'a mut R,
+    timeout: Duration,
+    deadline: Pin<Box<tokio::time::Sleep>>,
+    timed_out: bool,
+}
+
+impl<'a, R> IdleReader<'a, R> {
+    fn new(inner: &'a mut R, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+            timed_out: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for IdleReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        match Pin::new(&mut *this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    let next = tokio::time::Instant::now() + this.timeout;
+                    this.deadline.as_mut().reset(next);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => {
+                    this.timed_out = true;
+                    Poll::Ready(Err(std::io::ErrorKind::TimedOut.into()))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+// Plain TCP or TLS stream, so the protocol code doesn't care about the transport
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+type BoxedStream = Box<dyn AsyncStream>;
+
+// Largest frame on the wire, and largest frame after decompression
+const MAX_FRAME_SIZE: usize = 10_000_000;
+// Larger clipboard messages are split into chunks of this size
+const CHUNK_SIZE: usize = 1024 * 1024;
+// Largest clipboard message reassembled from chunks
+pub(crate) const MAX_TRANSFER_SIZE: usize = 512 * 1024 * 1024;
+// Smaller frames aren't worth compressing
+pub(crate) const COMPRESSION_THRESHOLD: usize = 8 * 1024;
+// With compression negotiated, every frame starts with one of these
+const FLAG_RAW: u8 = 0;
+const FLAG_ZSTD: u8 = 1;
+
+/// How frames are serialized on a connection
+#[derive(Debug, Clone, Copy)]
+struct Codec {
+    encoding: Encoding,
+    compression: Option<Compression>,
+}
+
+impl Codec {
+    // Authentication messages and hellos
+    const HANDSHAKE: Codec = Codec {
+        encoding: Encoding::Json,
+        compression: None,
+    };
+
+    fn negotiate(client: &Hello, server: &Hello) -> Self {
+        Self {
+            encoding: Hello::negotiate_encoding(client, server),
+            compression: Hello::negotiate_compression(client, server),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.compression {
+            Some(compression) => format!("{:?}, {:?}", self.encoding, compression),
+            None => format!("{:?}", self.encoding),
+        }
+    }
+
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>> {
+        match self.encoding {
+            // Named fields, so optional fields can be added like in JSON
+            Encoding::MessagePack => {
+                rmp_serde::to_vec_named(message).context("Failed to serialize message")
+            }
+            Encoding::Json | Encoding::Unknown => {
+                serde_json::to_vec(message).context("Failed to serialize message")
+            }
+        }
+    }
+
+    fn decode<T: for<'de> Deserialize<'de>>(&self, data: &[u8]) -> Result<T> {
+        match self.encoding {
+            Encoding::MessagePack => {
+                rmp_serde::from_slice(data).context("Failed to deserialize message")
+            }
+            Encoding::Json | Encoding::Unknown => {
+                serde_json::from_slice(data).context("Failed to deserialize message")
+            }
+        }
+    }
+}
+
+/// Why a frame couldn't be read, which decides whether the connection can go on
+#[derive(Debug)]
+enum FrameError {
+    /// The peer closed the connection between two frames
+    Closed,
+    /// A complete frame that couldn't be decompressed or decoded (e.g. from a newer
+    /// version), the next frame can still be read
+    Undecodable(anyhow::Error),
+    /// Nothing arrived for the heartbeat timeout
+    TimedOut(Duration),
+    /// The stream is broken or out of step with the frames
+    Broken(anyhow::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Closed => f.write_str("Connection closed"),
+            FrameError::Undecodable(e) | FrameError::Broken(e) => write!(f, "{:#}", e),
+            FrameError::TimedOut(timeout) => {
+                write!(f, "Heartbeat timed out after {}s", timeout.as_secs())
+            }
+        }
+    }
 }
 
+impl std::error::Error for FrameError {}
+
 // Helper functions for length-prefixed message protocol
-async fn read_message<T: for<'de> Deserialize<'de>>(reader: &mut OwnedReadHalf) -> Result<T> {
+
+// Returns the (decompressed) payload and its size on the wire if it was compressed
+async fn read_payload<R>(
+    reader: &mut R,
+    codec: Codec,
+) -> Result<(Vec<u8>, Option<usize>), FrameError>
+where
+    R: AsyncRead + Unpin,
+{
     // Read 4-byte length prefix (big-endian)
     let mut len_bytes = [0u8; 4];
-    reader
-        .read_exact(&mut len_bytes)
-        .await
-        .context("Failed to read message length")?;
+    if let Err(e) = reader.read_exact(&mut len_bytes).await {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            return Err(FrameError::Closed);
+        }
+        return Err(FrameError::Broken(
+            anyhow::Error::new(e).context("Failed to read message length"),
+        ));
+    }
     let len = u32::from_be_bytes(len_bytes) as usize;
 
     // Validate message length
-    if len == 0 || len > 10_000_000 {
-        // Max 10MB
-        anyhow::bail!("Invalid message length: {}", len);
+    if len == 0 || len > MAX_FRAME_SIZE {
+        return Err(FrameError::Broken(anyhow::anyhow!(
+            "Invalid message length: {}",
+            len
+        )));
     }
 
     // Read message data
@@ -53,16 +666,54 @@ async fn read_message<T: for<'de> Deserialize<'de>>(reader: &mut OwnedReadHalf)
     reader
         .read_exact(&mut buffer)
         .await
-        .context("Failed to read message data")?;
+        .context("Failed to read message data")
+        .map_err(FrameError::Broken)?;
+
+    if codec.compression.is_none() {
+        return Ok((buffer, None));
+    }
+    match buffer[0] {
+        FLAG_RAW => Ok((buffer.split_off(1), None)),
+        FLAG_ZSTD => {
+            // The frame has been read completely, so a bad one can be skipped
+            let data = zstd::bulk::decompress(&buffer[1..], MAX_FRAME_SIZE)
+                .context("Failed to decompress frame")
+                .map_err(FrameError::Undecodable)?;
+            Ok((data, Some(len)))
+        }
+        flag => Err(FrameError::Broken(anyhow::anyhow!(
+            "Unknown frame flag: {}",
+            flag
+        ))),
+    }
+}
 
-    // Deserialize JSON
-    serde_json::from_slice(&buffer).context("Failed to deserialize message")
+async fn read_message<T, R>(reader: &mut R, codec: Codec) -> Result<T, FrameError>
+where
+    T: for<'de> Deserialize<'de>,
+    R: AsyncRead + Unpin,
+{
+    let (data, _) = read_payload(reader, codec).await?;
+    codec.decode(&data).map_err(FrameError::Undecodable)
 }
 
-async fn write_message<T: Serialize>(writer: &mut OwnedWriteHalf, message: &T) -> Result<()> {
-    // Serialize to JSON
-    let data = serde_json::to_vec(message).context("Failed to serialize message")?;
+// Like read_message, but remembers compression stats on clipboard messages
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    codec: Codec,
+) -> Result<Frame, FrameError> {
+    let (data, compressed) = read_payload(reader, codec).await?;
+    let mut frame: Frame = codec.decode(&data).map_err(FrameError::Undecodable)?;
+    if let (Frame::Clipboard(message), Some(compressed)) = (&mut frame, compressed) {
+        message.compressed = Some((compressed, data.len()));
+    }
+    Ok(frame)
+}
 
+async fn write_payload<W>(writer: &mut W, data: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
     // Write length prefix (4 bytes, big-endian)
     let len = data.len() as u32;
     writer
@@ -72,7 +723,7 @@ async fn write_message<T: Serialize>(writer: &mut OwnedWriteHalf, message: &T) -
 
     // Write message data
     writer
-        .write_all(&data)
+        .write_all(data)
         .await
         .context("Failed to write message data")?;
 
@@ -81,180 +732,2304 @@ async fn write_message<T: Serialize>(writer: &mut OwnedWriteHalf, message: &T) -
     Ok(())
 }
 
-pub struct SyncServer {
-    addr: SocketAddr,
-    tx: mpsc::UnboundedSender<ClipboardMessage>,
-    broadcast_tx: broadcast::Sender<ClipboardMessage>,
+async fn write_frame<T, W>(writer: &mut W, message: &T, codec: Codec, compress: bool) -> Result<()>
+where
+    T: Serialize,
+    W: AsyncWrite + Unpin,
+{
+    let data = codec.encode(message)?;
+    write_encoded(writer, &data, codec, compress).await
 }
 
-impl SyncServer {
-    pub fn new(
-        addr: SocketAddr,
-        tx: mpsc::UnboundedSender<ClipboardMessage>,
-        broadcast_tx: broadcast::Sender<ClipboardMessage>,
-    ) -> Self {
-        Self {
-            addr,
-            tx,
-            broadcast_tx,
+async fn write_encoded<W>(writer: &mut W, data: &[u8], codec: Codec, compress: bool) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let Some(compression) = codec.compression else {
+        return write_payload(writer, data).await;
+    };
+
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    let compressed = match compression {
+        Compression::Zstd if compress && data.len() > COMPRESSION_THRESHOLD => {
+            Some(zstd::bulk::compress(data, 0).context("Failed to compress message")?)
+        }
+        _ => None,
+    };
+    match compressed {
+        Some(compressed) if compressed.len() < data.len() => {
+            frame.push(FLAG_ZSTD);
+            frame.extend_from_slice(&compressed);
+        }
+        _ => {
+            frame.push(FLAG_RAW);
+            frame.extend_from_slice(data);
         }
     }
+    write_payload(writer, &frame).await
+}
 
-    pub async fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
-        println!("Server listening on {}", self.addr);
+async fn write_message<T, W>(writer: &mut W, message: &T, codec: Codec) -> Result<()>
+where
+    T: Serialize,
+    W: AsyncWrite + Unpin,
+{
+    write_frame(writer, message, codec, true).await
+}
 
-        loop {
-            let (socket, addr) = listener.accept().await?;
-            println!("New connection from {}", addr);
+// Percentage done after chunk `index` of `total`, logged about every quarter
+fn progress(index: u32, total: u32) -> Option<u64> {
+    let (done, total) = (index as u64 + 1, total as u64);
+    let crossed_quarter = done * 4 / total > (done - 1) * 4 / total;
+    (crossed_quarter && done < total).then_some(done * 100 / total)
+}
 
-            let tx = self.tx.clone();
-            let broadcast_rx = self.broadcast_tx.subscribe();
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_client(socket, tx, broadcast_rx).await {
-                    eprintln!("Error handling client {}: {}", addr, e);
-                }
-            });
+/// Write side of a connection after the handshake.
+struct FrameWriter<W> {
+    writer: W,
+    codec: Codec,
+    // Whether the peer reassembles chunked transfers
+    chunked: bool,
+    // Used in log lines, e.g. "client 1.2.3.4:5678"
+    peer: String,
+    next_transfer_id: u64,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    fn new(writer: W, codec: Codec, peer: &Hello, name: String) -> Self {
+        Self {
+            writer,
+            codec,
+            chunked: peer.chunked,
+            peer: name,
+            next_transfer_id: 0,
         }
     }
 
-    async fn handle_client(
-        socket: TcpStream,
-        tx: mpsc::UnboundedSender<ClipboardMessage>,
-        mut broadcast_rx: broadcast::Receiver<ClipboardMessage>,
+    async fn control(&mut self, control: Control) -> Result<()> {
+        write_message(&mut self.writer, &Frame::Control(control), self.codec).await
+    }
+
+    /// Send a clipboard message, split into chunks when it doesn't fit into one frame.
+    ///
+    /// Between chunks, pending `replies` (pongs, acks) are written so the peer doesn't
+    /// time out waiting behind a long transfer, and `superseded` is checked; once it
+    /// returns true the transfer is cancelled so the newer content isn't stuck behind it.
+    async fn send(
+        &mut self,
+        message: &ClipboardMessage,
+        replies: &mut mpsc::UnboundedReceiver<Control>,
+        mut superseded: impl FnMut() -> bool,
     ) -> Result<()> {
-        let (mut read_half, mut write_half) = socket.into_split();
+        let compress = message.content.compressible();
+        let data = self.codec.encode(message)?;
 
-        // Task to receive messages from client
-        let receive_handle = tokio::spawn(async move {
-            loop {
-                match read_message::<ClipboardMessage>(&mut read_half).await {
-                    Ok(message) => {
-                        if let Err(e) = tx.send(message) {
-                            eprintln!("Failed to send to channel: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        if e.to_string().contains("Failed to read message length") {
-                            // Connection closed
-                            break;
-                        }
-                        eprintln!("Error reading from client: {}", e);
-                        break;
-                    }
-                }
-            }
-        });
+        if data.len() <= CHUNK_SIZE || (!self.chunked && data.len() < MAX_FRAME_SIZE) {
+            return write_encoded(&mut self.writer, &data, self.codec, compress).await;
+        }
+        if !self.chunked {
+            println!(
+                "Skipping {} for {}: too large for a single frame",
+                message.content.summary(),
+                self.peer
+            );
+            return Ok(());
+        }
 
-        // Task to broadcast messages to client
-        let broadcast_handle = tokio::spawn(async move {
-            loop {
-                match broadcast_rx.recv().await {
-                    Ok(message) => {
-                        if let Err(e) = write_message(&mut write_half, &message).await {
-                            eprintln!("Failed to write to client: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Broadcast receive error: {}", e);
-                        break;
-                    }
-                }
+        self.next_transfer_id += 1;
+        let transfer_id = self.next_transfer_id;
+        let total = data.len().div_ceil(CHUNK_SIZE) as u32;
+        println!(
+            "Sending {} to {} in {} chunks (transfer {})",
+            message.content.summary(),
+            self.peer,
+            total,
+            transfer_id
+        );
+
+        for (index, piece) in data.chunks(CHUNK_SIZE).enumerate() {
+            let index = index as u32;
+            while let Ok(reply) = replies.try_recv() {
+                self.control(reply).await?;
+            }
+            if index > 0 && superseded() {
+                println!(
+                    "Transfer {} to {} cancelled, superseded by newer content",
+                    transfer_id, self.peer
+                );
+                let cancel = Frame::Control(Control::Cancel { transfer_id });
+                return write_message(&mut self.writer, &cancel, self.codec).await;
             }
-        });
 
-        // Wait for either task to complete
-        tokio::select! {
-            _ = receive_handle => {},
-            _ = broadcast_handle => {},
-        }
+            let chunk = Chunk {
+                transfer_id,
+                index,
+                total,
+                size: data.len() as u64,
+                data: piece.to_vec(),
+            };
+            let frame = Frame::Control(Control::Chunk(chunk));
+            write_frame(&mut self.writer, &frame, self.codec, compress).await?;
 
+            if let Some(percent) = progress(index, total) {
+                println!("Transfer {} to {}: {}%", transfer_id, self.peer, percent);
+            }
+        }
         Ok(())
     }
 }
 
-#[derive(Clone)]
-pub struct SyncClient {
-    addr: SocketAddr,
-    client_id: String,
+// Whether `message` would be sent to a peer that introduced itself with `peer`
+fn deliverable(message: &ClipboardMessage, peer: &Hello) -> bool {
+    (message.selection != Selection::Primary || peer.primary)
+        && message.content.downgrade_for(&peer.content_kinds).is_some()
 }
 
-impl SyncClient {
-    pub fn new(addr: SocketAddr, client_id: String) -> Self {
-        Self { addr, client_id }
+/// Messages waiting to be written to a connection.
+///
+/// While a chunked transfer runs, queued messages are taken out early to see whether
+/// one of them supersedes it; they are handed out again afterwards, in order.
+struct Pending<F> {
+    rx: broadcast::Receiver<ClipboardMessage>,
+    // Taken out early, together with whether they are sent to the peer at all
+    taken: VecDeque<(ClipboardMessage, bool)>,
+    deliverable: F,
+}
+
+impl<F: Fn(&ClipboardMessage) -> bool> Pending<F> {
+    fn new(rx: broadcast::Receiver<ClipboardMessage>, deliverable: F) -> Self {
+        Self {
+            rx,
+            taken: VecDeque::new(),
+            deliverable,
+        }
     }
 
-    pub async fn connect_bidirectional(
-        &self,
-        tx: mpsc::UnboundedSender<ClipboardMessage>,
-        mut rx: broadcast::Receiver<ClipboardContent>,
-    ) -> Result<()> {
-        let stream = TcpStream::connect(self.addr).await?;
-        println!("Connected to server at {}", self.addr);
+    async fn recv(&mut self) -> Result<ClipboardMessage, broadcast::error::RecvError> {
+        match self.taken.pop_front() {
+            Some((message, _)) => Ok(message),
+            None => self.rx.recv().await,
+        }
+    }
 
-        let (mut read_half, mut write_half) = stream.into_split();
+    // Only a newer message of the same selection that the peer will get replaces the
+    // one being sent; PRIMARY doesn't cancel a clipboard transfer and vice versa
+    fn supersedes(&mut self, selection: Selection) -> bool {
+        loop {
+            match self.rx.try_recv() {
+                Ok(message) => {
+                    let deliverable = (self.deliverable)(&message);
+                    self.taken.push_back((message, deliverable));
+                }
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        self.taken
+            .iter()
+            .any(|(message, deliverable)| *deliverable && message.selection == selection)
+    }
+}
 
-        // Task to receive messages from server
-        let receive_handle = tokio::spawn(async move {
-            loop {
-                match read_message::<ClipboardMessage>(&mut read_half).await {
-                    Ok(message) => {
-                        if let Err(e) = tx.send(message) {
-                            eprintln!("Failed to send received message: {}", e);
+struct Transfer {
+    id: u64,
+    total: u32,
+    size: usize,
+    data: Vec<u8>,
+}
+
+/// Reassembles chunked transfers arriving on one connection.
+struct Transfers {
+    codec: Codec,
+    // Used in log lines, e.g. "client 1.2.3.4:5678"
+    peer: String,
+    // The per-connection limit on concurrent transfers is one: chunks arrive in order, and
+    // a new transfer replaces the one in flight. A peer can't make us hold more than one
+    // MAX_TRANSFER_SIZE buffer, and only as much of it as it actually sent.
+    current: Option<Transfer>,
+}
+
+impl Transfers {
+    fn new(codec: Codec, peer: String) -> Self {
+        Self {
+            codec,
+            peer,
+            current: None,
+        }
+    }
+
+    /// Add a chunk, returns the message once its last chunk has arrived
+    fn receive(&mut self, chunk: Chunk) -> Option<ClipboardMessage> {
+        if chunk.index == 0 {
+            if let Some(previous) = self.current.take() {
+                println!(
+                    "Transfer {} from {} superseded by transfer {}",
+                    previous.id, self.peer, chunk.transfer_id
+                );
+            }
+            let size = chunk.size as usize;
+            if size > MAX_TRANSFER_SIZE || chunk.total == 0 {
+                eprintln!(
+                    "Rejecting transfer {} from {}: {} bytes exceeds the limit of {} bytes",
+                    chunk.transfer_id, self.peer, size, MAX_TRANSFER_SIZE
+                );
+                return None;
+            }
+            println!(
+                "Receiving transfer {} from {}: {} bytes in {} chunks",
+                chunk.transfer_id, self.peer, size, chunk.total
+            );
+            self.current = Some(Transfer {
+                id: chunk.transfer_id,
+                total: chunk.total,
+                size,
+                // The size is the peer's claim, the buffer grows as the chunks arrive
+                data: Vec::with_capacity(size.min(CHUNK_SIZE)),
+            });
+        }
+
+        // Chunks of rejected or cancelled transfers end up here too
+        let transfer = self.current.as_mut()?;
+        if transfer.id != chunk.transfer_id
+            || transfer.data.len() as u64 != chunk.index as u64 * CHUNK_SIZE as u64
+            || transfer.data.len() + chunk.data.len() > transfer.size
+        {
+            eprintln!(
+                "Dropping transfer {} from {}: unexpected chunk {} of transfer {}",
+                transfer.id, self.peer, chunk.index, chunk.transfer_id
+            );
+            self.current = None;
+            return None;
+        }
+
+        transfer.data.extend_from_slice(&chunk.data);
+        if let Some(percent) = progress(chunk.index, transfer.total) {
+            println!("Transfer {} from {}: {}%", transfer.id, self.peer, percent);
+        }
+        if chunk.index + 1 < transfer.total {
+            return None;
+        }
+
+        let transfer = self.current.take()?;
+        match self.codec.decode(&transfer.data) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                eprintln!(
+                    "Skipping undecodable transfer {} from {}: {}",
+                    transfer.id, self.peer, e
+                );
+                None
+            }
+        }
+    }
+
+    fn cancel(&mut self, transfer_id: u64) {
+        if self.current.as_ref().map(|transfer| transfer.id) == Some(transfer_id) {
+            self.current = None;
+            println!("Transfer {} from {} cancelled", transfer_id, self.peer);
+        }
+    }
+}
+
+async fn authenticate_client(stream: &mut BoxedStream, token: &AuthToken) -> Result<()> {
+    let nonce = AuthToken::challenge();
+    write_message(
+        stream,
+        &AuthMessage::Challenge {
+            nonce: nonce.clone(),
+        },
+        Codec::HANDSHAKE,
+    )
+    .await?;
+
+    let verified = match read_message::<AuthMessage, _>(stream, Codec::HANDSHAKE).await? {
+        AuthMessage::Response { mac } => token.verify(&nonce, &mac),
+        other => anyhow::bail!("Unexpected handshake message: {:?}", other),
+    };
+
+    if !verified {
+        let reason = "invalid token".to_string();
+        // Best effort, the socket is closed either way
+        let _ = write_message(stream, &AuthMessage::Rejected { reason }, Codec::HANDSHAKE).await;
+        anyhow::bail!("Invalid token");
+    }
+
+    write_message(stream, &AuthMessage::Accepted, Codec::HANDSHAKE).await
+}
+
+async fn authenticate_to_server(stream: &mut BoxedStream, token: &AuthToken) -> Result<()> {
+    let nonce = match read_message::<AuthMessage, _>(stream, Codec::HANDSHAKE).await? {
+        AuthMessage::Challenge { nonce } => nonce,
+        other => anyhow::bail!("Unexpected handshake message: {:?}", other),
+    };
+
+    let mac = token.respond(&nonce);
+    write_message(stream, &AuthMessage::Response { mac }, Codec::HANDSHAKE).await?;
+
+    match read_message::<AuthMessage, _>(stream, Codec::HANDSHAKE).await? {
+        AuthMessage::Accepted => Ok(()),
+        AuthMessage::Rejected { reason } => anyhow::bail!("Authentication rejected: {}", reason),
+        other => anyhow::bail!("Unexpected handshake message: {:?}", other),
+    }
+}
+
+async fn negotiate_with_client(
+    stream: BoxedStream,
+    addr: SocketAddr,
+    tx: &mpsc::UnboundedSender<ClipboardMessage>,
+    hello: Hello,
+    hello_timeout: Duration,
+    room_tokens: &HashMap<String, AuthToken>,
+) -> Result<(BoxedStream, Hello)> {
+    let mut stream = BufReader::new(stream);
+
+    // Unlike read_message, fill_buf doesn't lose data when the timeout fires
+    let has_data = match tokio::time::timeout(hello_timeout, stream.fill_buf()).await {
+        Ok(buf) => !buf?.is_empty(),
+        Err(_) => false,
+    };
+    if !has_data {
+        return Ok((Box::new(stream), Hello::legacy()));
+    }
+
+    // The hellos themselves are always JSON
+    let frame = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        read_message::<Frame, _>(&mut stream, Codec::HANDSHAKE),
+    )
+    .await
+    .context("Timed out reading handshake")??;
+    let peer = match frame {
+        Frame::Control(Control::Hello(peer)) => {
+            let room_token = peer.room.as_ref().and_then(|room| room_tokens.get(room));
+            let hello = Hello {
+                room: peer.room.clone(),
+                room_auth: room_token.is_some(),
+                ..hello
+            };
+            write_message(
+                &mut stream,
+                &Frame::Control(Control::Hello(hello)),
+                Codec::HANDSHAKE,
+            )
+            .await?;
+            if let Some(token) = room_token {
+                let mut boxed: BoxedStream = Box::new(stream);
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, authenticate_client(&mut boxed, token))
+                    .await
+                    .context("Timed out authenticating for the room")?
+                    .context("Room authentication failed")?;
+                return Ok((boxed, peer));
+            }
+            peer
+        }
+        Frame::Clipboard(mut message) => {
+            // Legacy client that copied something right after connecting, it is never
+            // sent back there either
+            message.source = Some(addr);
+            // It didn't introduce itself, so it has no name to go by
+            message.device_name = None;
+            if let Err(e) = tx.send(message) {
+                eprintln!("Failed to send to channel: {}", e);
+            }
+            Hello::legacy()
+        }
+        Frame::Control(_) => anyhow::bail!("Client did not start with a hello"),
+    };
+
+    Ok((Box::new(stream), peer))
+}
+
+// None when the server closes the connection or stays silent instead of answering,
+// which is what versions from before the hello do
+async fn negotiate_with_server(stream: &mut BoxedStream, hello: Hello) -> Result<Option<Hello>> {
+    write_message(
+        stream,
+        &Frame::Control(Control::Hello(hello)),
+        Codec::HANDSHAKE,
+    )
+    .await?;
+
+    let frame = match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        read_message::<Frame, _>(stream, Codec::HANDSHAKE),
+    )
+    .await
+    {
+        Ok(Ok(frame)) => frame,
+        Ok(Err(FrameError::Closed)) | Err(_) => return Ok(None),
+        Ok(Err(e)) => return Err(e).context("Handshake failed"),
+    };
+    match frame {
+        Frame::Control(Control::Hello(hello)) => Ok(Some(hello)),
+        _ => anyhow::bail!("Server did not answer the hello"),
+    }
+}
+
+// Resolves on the next connection, never without a listener
+async fn accept_on(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Broadcast channel of a room and the latest message sent to it.
+#[derive(Clone)]
+struct Room {
+    tx: broadcast::Sender<ClipboardMessage>,
+    // Replayed to clients when they join, None when replay is disabled
+    latest: Option<Arc<Mutex<Option<ClipboardMessage>>>>,
+}
+
+impl Room {
+    fn new(tx: broadcast::Sender<ClipboardMessage>, replay: bool) -> Self {
+        let latest = replay.then(|| {
+            // Broadcast receivers only see messages sent after subscribe(), so keep
+            // track of the latest one ourselves
+            let latest = Arc::new(Mutex::new(None));
+            let tracked = latest.clone();
+            let mut rx = tx.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    match rx.recv().await {
+                        // PRIMARY changes with every mouse selection, new clients only
+                        // get the clipboard
+                        Ok(message) if message.selection == Selection::Primary => continue,
+                        Ok(message) => *tracked.lock().unwrap() = Some(message),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            });
+            latest
+        });
+        Self { tx, latest }
+    }
+
+    fn latest(&self) -> Option<ClipboardMessage> {
+        self.latest
+            .as_ref()
+            .and_then(|latest| latest.lock().unwrap().clone())
+    }
+}
+
+/// Where `SyncServer` broadcasts messages to.
+///
+/// Clients that don't ask for a room share the channel passed to `SyncServer::new`.
+/// Named rooms get their own channel when the first client joins and lose it when the
+/// last one leaves, so messages never cross from one room to another.
+#[derive(Clone)]
+pub struct Rooms {
+    shared: broadcast::Sender<ClipboardMessage>,
+    // Each named room with its number of clients
+    named: Arc<Mutex<HashMap<String, (Room, usize)>>>,
+    // Server-wide, the same for every room whoever joins it first
+    replay: bool,
+}
+
+impl Rooms {
+    fn new(shared: broadcast::Sender<ClipboardMessage>) -> Self {
+        Self {
+            shared,
+            named: Arc::new(Mutex::new(HashMap::new())),
+            replay: true,
+        }
+    }
+
+    // Rooms are created on demand by unauthenticated names, so their number is capped
+    fn join(&self, name: &str) -> Result<(Room, Membership)> {
+        if name.len() > MAX_ROOM_NAME {
+            anyhow::bail!("room name longer than {} bytes", MAX_ROOM_NAME);
+        }
+        let mut named = self.named.lock().unwrap();
+        if !named.contains_key(name) && named.len() >= MAX_ROOMS {
+            anyhow::bail!("too many rooms ({})", MAX_ROOMS);
+        }
+        let (room, members) = named.entry(name.to_string()).or_insert_with(|| {
+            (
+                Room::new(broadcast::channel(ROOM_CAPACITY).0, self.replay),
+                0,
+            )
+        });
+        *members += 1;
+        let membership = Membership {
+            rooms: self.clone(),
+            name: name.to_string(),
+        };
+        Ok((room.clone(), membership))
+    }
+
+    fn leave(&self, name: &str) {
+        let mut named = self.named.lock().unwrap();
+        if let Some((_, members)) = named.get_mut(name) {
+            *members -= 1;
+            if *members == 0 {
+                named.remove(name);
+            }
+        }
+    }
+
+    /// Broadcast the message to the room it came from
+    pub fn send(&self, message: ClipboardMessage) -> Result<()> {
+        let tx = match &message.room {
+            Some(room) => match self.named.lock().unwrap().get(room) {
+                Some((room, _)) => room.tx.clone(),
+                None => anyhow::bail!("no room named {}", room),
+            },
+            None => self.shared.clone(),
+        };
+        tx.send(message)
+            .map_err(|_| anyhow::anyhow!("channel closed"))?;
+        Ok(())
+    }
+}
+
+// A client's place in a named room, leaving it when dropped
+struct Membership {
+    rooms: Rooms,
+    name: String,
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.rooms.leave(&self.name);
+    }
+}
+
+pub struct SyncServer {
+    addr: SocketAddr,
+    // Additional listener for clients connecting over WebSocket
+    websocket: Option<SocketAddr>,
+    tx: mpsc::UnboundedSender<ClipboardMessage>,
+    rooms: Rooms,
+    tls: Option<TlsAcceptor>,
+    token: Option<AuthToken>,
+    // Rooms only clients with the room's token may join
+    room_tokens: Arc<HashMap<String, AuthToken>>,
+    // mDNS group the server is advertised under
+    discovery: Option<String>,
+    heartbeat: Option<Heartbeat>,
+    // How long to wait for a client's hello before treating it as a legacy client
+    hello_timeout: Duration,
+    // What we tell clients about ourselves
+    hello: Hello,
+}
+
+impl SyncServer {
+    pub fn new(
+        addr: SocketAddr,
+        tx: mpsc::UnboundedSender<ClipboardMessage>,
+        broadcast_tx: broadcast::Sender<ClipboardMessage>,
+    ) -> Self {
+        Self {
+            addr,
+            websocket: None,
+            tx,
+            rooms: Rooms::new(broadcast_tx),
+            tls: None,
+            token: None,
+            room_tokens: Arc::new(HashMap::new()),
+            discovery: None,
+            heartbeat: Some(Heartbeat::default()),
+            hello_timeout: HELLO_TIMEOUT,
+            hello: Hello::current(),
+        }
+    }
+
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Send our device id and name to clients in the handshake
+    pub fn with_identity(mut self, identity: &Identity) -> Self {
+        self.hello = Hello::current().with_identity(identity);
+        self
+    }
+
+    /// Also accept WebSocket connections on `addr`, with TLS if the server uses it
+    pub fn with_websocket(mut self, addr: SocketAddr) -> Self {
+        self.websocket = Some(addr);
+        self
+    }
+
+    /// Advertise the server on the local network so clients can find it without an address
+    pub fn with_discovery(mut self, group: String) -> Self {
+        self.discovery = Some(group);
+        self
+    }
+
+    /// Send the latest clipboard to clients as soon as they connect (enabled by default)
+    pub fn with_replay(mut self, enabled: bool) -> Self {
+        self.rooms.replay = enabled;
+        self
+    }
+
+    pub fn with_token(mut self, token: AuthToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Only let clients that know `token` join `room`
+    pub fn with_room_token(mut self, room: String, token: AuthToken) -> Self {
+        Arc::make_mut(&mut self.room_tokens).insert(room, token);
+        self
+    }
+
+    /// Handle for broadcasting messages to the room they came from
+    pub fn rooms(&self) -> Rooms {
+        self.rooms.clone()
+    }
+
+    /// Ping clients and drop the ones that stop responding, None disables it
+    pub fn with_heartbeat(mut self, heartbeat: Option<Heartbeat>) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// How long a client may stay silent before it is served as a legacy client (3s by
+    /// default). Current clients send their hello right away; legacy ones wait this long
+    /// for their first message.
+    pub fn with_hello_timeout(mut self, timeout: Duration) -> Self {
+        self.hello_timeout = timeout;
+        self
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        println!("Server listening on {}", self.addr);
+
+        // Kept alive for as long as the server accepts connections
+        let _advertisement = match &self.discovery {
+            Some(group) => {
+                let port = listener.local_addr()?.port();
+                match discovery::advertise(port, group, self.tls.is_some()) {
+                    Ok(advertisement) => {
+                        println!("Advertising on the local network (group: {})", group);
+                        Some(advertisement)
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to advertise via mDNS: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let websocket = match self.websocket {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let scheme = if self.tls.is_some() { "wss" } else { "ws" };
+                println!("WebSocket endpoint listening on {}://{}", scheme, addr);
+                Some(listener)
+            }
+            None => None,
+        };
+
+        let shared = Room::new(self.rooms.shared.clone(), self.rooms.replay);
+
+        loop {
+            let (socket, addr, is_websocket) = tokio::select! {
+                accepted = listener.accept() => {
+                    let (socket, addr) = accepted?;
+                    (socket, addr, false)
+                }
+                accepted = accept_on(websocket.as_ref()) => {
+                    let (socket, addr) = accepted?;
+                    (socket, addr, true)
+                }
+            };
+            println!(
+                "New {}connection from {}",
+                if is_websocket { "WebSocket " } else { "" },
+                addr
+            );
+
+            let tx = self.tx.clone();
+            let shared_rx = shared.tx.subscribe();
+            let shared = shared.clone();
+            let rooms = self.rooms.clone();
+            let tls = self.tls.clone();
+            let token = self.token.clone();
+            let room_tokens = self.room_tokens.clone();
+            let heartbeat = self.heartbeat;
+            let hello_timeout = self.hello_timeout;
+            let hello = self.hello.clone();
+            tokio::spawn(async move {
+                // Handshake inside the task so a slow client can't stall the accept loop
+                let mut stream: BoxedStream = match tls {
+                    Some(acceptor) => match acceptor.accept(socket).await {
+                        Ok(stream) => Box::new(stream),
+                        Err(e) => {
+                            eprintln!("TLS handshake with {} failed: {}", addr, e);
+                            return;
+                        }
+                    },
+                    None => Box::new(socket),
+                };
+
+                if is_websocket {
+                    stream =
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, websocket::accept(stream))
+                            .await
+                        {
+                            Ok(Ok(stream)) => Box::new(stream),
+                            Ok(Err(e)) => {
+                                eprintln!("WebSocket handshake with {} failed: {:#}", addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                eprintln!("WebSocket handshake timed out for {}", addr);
+                                return;
+                            }
+                        };
+                }
+
+                if let Some(token) = token {
+                    match tokio::time::timeout(
+                        HANDSHAKE_TIMEOUT,
+                        authenticate_client(&mut stream, &token),
+                    )
+                    .await
+                    {
+                        Ok(Ok(())) => println!("Client {} authenticated", addr),
+                        Ok(Err(e)) => {
+                            eprintln!("Authentication failed for {}: {}", addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            eprintln!("Authentication timed out for {}", addr);
+                            return;
+                        }
+                    }
+                }
+
+                let (stream, peer) = match negotiate_with_client(
+                    stream,
+                    addr,
+                    &tx,
+                    hello,
+                    hello_timeout,
+                    &room_tokens,
+                )
+                .await
+                {
+                    Ok(negotiated) => negotiated,
+                    Err(e) => {
+                        eprintln!("Handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                };
+                // Pinging a client that can't answer would only time it out
+                let heartbeat = heartbeat.filter(|_| peer.heartbeat);
+
+                // The membership is held until the client disconnects
+                let (broadcast_rx, replay, _membership) = match &peer.room {
+                    Some(name) => match rooms.join(name) {
+                        Ok((room, membership)) => {
+                            (room.tx.subscribe(), room.latest(), Some(membership))
+                        }
+                        Err(e) => {
+                            eprintln!("Client {} can't join room {}: {}", addr, name, e);
+                            return;
+                        }
+                    },
+                    None => (shared_rx, shared.latest(), None),
+                };
+                if let Err(e) =
+                    Self::handle_client(stream, tx, broadcast_rx, replay, peer, heartbeat, addr)
+                        .await
+                {
+                    eprintln!("Error handling client {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_client(
+        stream: BoxedStream,
+        tx: mpsc::UnboundedSender<ClipboardMessage>,
+        broadcast_rx: broadcast::Receiver<ClipboardMessage>,
+        replay: Option<ClipboardMessage>,
+        peer: Hello,
+        heartbeat: Option<Heartbeat>,
+        addr: SocketAddr,
+    ) -> Result<()> {
+        let codec = Codec::negotiate(&peer, &Hello::current());
+        println!(
+            "Client {} connected ({}, encoding: {})",
+            addr,
+            peer.describe(),
+            codec.describe()
+        );
+
+        let (mut read_half, write_half) = tokio::io::split(stream);
+        // Pongs and acks are written by the broadcast task, which owns the write half
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        let device_name = peer.device_name.clone();
+        let room = peer.room.clone();
+
+        // Task to receive messages from client
+        let mut receive_handle = tokio::spawn(async move {
+            let mut transfers = Transfers::new(codec, format!("client {}", addr));
+            loop {
+                let mut message = match read_frame_timeout(&mut read_half, codec, heartbeat).await {
+                    Ok(Frame::Clipboard(message)) => message,
+                    Ok(Frame::Control(Control::Chunk(chunk))) => match transfers.receive(chunk) {
+                        Some(message) => message,
+                        None => continue,
+                    },
+                    Ok(Frame::Control(Control::Cancel { transfer_id })) => {
+                        transfers.cancel(transfer_id);
+                        continue;
+                    }
+                    Ok(Frame::Control(Control::Ping)) => {
+                        let _ = reply_tx.send(Control::Pong);
+                        continue;
+                    }
+                    Ok(Frame::Control(Control::Pong | Control::Ack { .. })) => continue,
+                    Ok(Frame::Control(Control::Hello(_))) => {
+                        eprintln!("Ignoring repeated hello from client {}", addr);
+                        continue;
+                    }
+                    // Content we don't understand (newer client), keep the connection
+                    Err(FrameError::Undecodable(e)) => {
+                        eprintln!("Skipping undecodable message from client {}: {:#}", addr, e);
+                        continue;
+                    }
+                    Err(FrameError::Closed) => break,
+                    Err(e) => {
+                        eprintln!("Error reading from client {}: {}", addr, e);
+                        break;
+                    }
+                };
+                message.source = Some(addr);
+                message.room = room.clone();
+                // Messages from the connection belong to the device it introduced itself as,
+                // whatever name they carry
+                message.device_name = device_name.clone();
+                let seq = message.seq;
+                if let Err(e) = tx.send(message) {
+                    eprintln!("Failed to send to channel: {}", e);
+                    break;
+                }
+                if let Some(seq) = seq {
+                    let _ = reply_tx.send(Control::Ack { seq });
+                }
+            }
+        });
+
+        // Task to broadcast messages to client
+        let mut broadcast_handle = tokio::spawn(async move {
+            let mut writer = FrameWriter::new(write_half, codec, &peer, format!("client {}", addr));
+            let client = peer.clone();
+            // The replayed message can also be waiting in the broadcast receiver, when it
+            // was sent after the client subscribed but before the replay was taken
+            let replayed = replay.as_ref().and_then(ClipboardMessage::version);
+            let is_replayed = move |message: &ClipboardMessage| {
+                replayed.is_some() && message.version() == replayed
+            };
+            let also_replayed = is_replayed.clone();
+            // The client already has what it sent
+            let mut pending = Pending::new(broadcast_rx, move |message: &ClipboardMessage| {
+                message.source != Some(addr)
+                    && !also_replayed(message)
+                    && deliverable(message, &client)
+            });
+            if let Some(message) = replay {
+                println!(
+                    "Sending latest clipboard to client {}: {}",
+                    addr,
+                    message.content.summary()
+                );
+                if let Err(e) =
+                    Self::send_to_client(&mut writer, message, &peer, &mut pending, &mut reply_rx)
+                        .await
+                {
+                    eprintln!("Failed to write to client: {}", e);
+                    return;
+                }
+            }
+
+            let mut ticker = heartbeat.map(|heartbeat| heartbeat.ticker());
+            loop {
+                let result = tokio::select! {
+                    received = pending.recv() => match received {
+                        // The client already has what it sent, or got it as the replay
+                        Ok(message) if message.source == Some(addr) || is_replayed(&message) => {
+                            continue
+                        }
+                        Ok(message) => {
+                            Self::send_to_client(
+                                &mut writer,
+                                message,
+                                &peer,
+                                &mut pending,
+                                &mut reply_rx,
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            eprintln!("Broadcast receive error: {}", e);
                             break;
                         }
+                    },
+                    Some(reply) = reply_rx.recv() => writer.control(reply).await,
+                    _ = next_ping(&mut ticker) => writer.control(Control::Ping).await,
+                };
+                if let Err(e) = result {
+                    eprintln!("Failed to write to client: {}", e);
+                    break;
+                }
+            }
+        });
+
+        // Wait for either task to complete, then tear down the other one
+        tokio::select! {
+            _ = &mut receive_handle => {},
+            _ = &mut broadcast_handle => {},
+        }
+        receive_handle.abort();
+        broadcast_handle.abort();
+
+        Ok(())
+    }
+
+    // Downgrade the message to what the client understands, skipping it if impossible
+    async fn send_to_client<W: AsyncWrite + Unpin>(
+        writer: &mut FrameWriter<W>,
+        mut message: ClipboardMessage,
+        peer: &Hello,
+        pending: &mut Pending<impl Fn(&ClipboardMessage) -> bool>,
+        replies: &mut mpsc::UnboundedReceiver<Control>,
+    ) -> Result<()> {
+        if message.selection == Selection::Primary && !peer.primary {
+            return Ok(());
+        }
+        match message.content.downgrade_for(&peer.content_kinds) {
+            Some(content) => message.content = content,
+            None => {
+                println!(
+                    "Skipping {} for {}: not supported by peer",
+                    message.content.summary(),
+                    writer.peer
+                );
+                return Ok(());
+            }
+        }
+        // A newer message waiting behind this one cancels a chunked transfer
+        let selection = message.selection;
+        writer
+            .send(&message, replies, || pending.supersedes(selection))
+            .await
+    }
+}
+
+/// Sequence numbers of a client and its latest unacknowledged message per selection.
+///
+/// Shared by successive connections of one client, so a message lost with a dropped
+/// connection is sent again after reconnecting. Only the latest message of each
+/// selection is kept, older ones have been superseded on that selection anyway.
+#[derive(Clone, Default)]
+pub struct Outbox {
+    state: Arc<Mutex<OutboxState>>,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    last_seq: u64,
+    unacked: HashMap<Selection, ClipboardMessage>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Number the message and keep it until the server acknowledges it
+    fn track(&self, message: &mut ClipboardMessage) {
+        let mut state = self.state.lock().unwrap();
+        state.last_seq += 1;
+        message.seq = Some(state.last_seq);
+        state.unacked.insert(message.selection, message.clone());
+    }
+
+    // The server handles a connection's messages in order, so an ack covers earlier ones
+    fn ack(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .unacked
+            .retain(|_, message| message.seq.is_some_and(|unacked| unacked > seq));
+    }
+
+    // Oldest first, the order they were copied in
+    fn unacked(&self) -> Vec<ClipboardMessage> {
+        let mut unacked: Vec<_> = self
+            .state
+            .lock()
+            .unwrap()
+            .unacked
+            .values()
+            .cloned()
+            .collect();
+        unacked.sort_by_key(|message| message.seq);
+        unacked
+    }
+}
+
+/// Where `SyncClient` connects to.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    WebSocket(WebSocketUrl),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::WebSocket(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SyncClient {
+    endpoint: Endpoint,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    cipher: Option<ContentCipher>,
+    token: Option<AuthToken>,
+    heartbeat: Option<Heartbeat>,
+    outbox: Outbox,
+    room_token: Option<AuthToken>,
+    hello: Hello,
+}
+
+impl SyncClient {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            tls: None,
+            cipher: None,
+            token: None,
+            heartbeat: Some(Heartbeat::default()),
+            outbox: Outbox::new(),
+            room_token: None,
+            hello: Hello::current(),
+        }
+    }
+
+    /// Send our device id and name to the server in the handshake
+    pub fn with_identity(mut self, identity: &Identity) -> Self {
+        self.hello = self.hello.with_identity(identity);
+        self
+    }
+
+    /// Join `room` on the server instead of the shared one, answering its challenge
+    /// with `token` if the room has one
+    pub fn with_room(mut self, room: String, token: Option<AuthToken>) -> Self {
+        self.hello = self.hello.with_room(Some(room));
+        self.room_token = token;
+        self
+    }
+
+    pub fn with_tls(mut self, connector: TlsConnector, server_name: ServerName<'static>) -> Self {
+        self.tls = Some((connector, server_name));
+        self
+    }
+
+    pub fn with_encryption(mut self, cipher: ContentCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    pub fn with_token(mut self, token: AuthToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Ping the server and reconnect when it stops responding, None disables it
+    pub fn with_heartbeat(mut self, heartbeat: Option<Heartbeat>) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Share sequence numbers and the unacknowledged message with earlier connections
+    pub fn with_outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = outbox;
+        self
+    }
+
+    // Connection up to the hello: transport, TLS, WebSocket and token authentication
+    async fn open_stream(&self) -> Result<BoxedStream> {
+        let socket = match &self.endpoint {
+            Endpoint::Tcp(addr) => TcpStream::connect(addr).await?,
+            Endpoint::WebSocket(url) => websocket::connect_tcp(url).await?,
+        };
+        let mut stream: BoxedStream = match &self.tls {
+            Some((connector, server_name)) => {
+                let stream = connector
+                    .connect(server_name.clone(), socket)
+                    .await
+                    .context("TLS handshake failed")?;
+                println!("Connected to server at {} (TLS)", self.endpoint);
+                Box::new(stream)
+            }
+            None => {
+                println!("Connected to server at {}", self.endpoint);
+                Box::new(socket)
+            }
+        };
+
+        if let Endpoint::WebSocket(url) = &self.endpoint {
+            stream = Box::new(
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, websocket::client_handshake(url, stream))
+                    .await
+                    .context("Timed out waiting for WebSocket handshake")??,
+            );
+        }
+
+        if let Some(token) = &self.token {
+            tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                authenticate_to_server(&mut stream, token),
+            )
+            .await
+            .context("Timed out waiting for authentication")??;
+            println!("Authenticated with server");
+        }
+
+        Ok(stream)
+    }
+
+    pub async fn connect_bidirectional(
+        &self,
+        tx: mpsc::UnboundedSender<ClipboardMessage>,
+        rx: broadcast::Receiver<ClipboardMessage>,
+    ) -> Result<()> {
+        let mut stream = self.open_stream().await?;
+        let server = match negotiate_with_server(&mut stream, self.hello.clone()).await? {
+            Some(server) => server,
+            None => {
+                // Versions from before the hello don't understand it, talk to them the old way
+                println!("Server did not answer the hello, reconnecting with the legacy protocol");
+                stream = self.open_stream().await?;
+                Hello::legacy()
+            }
+        };
+        let codec = Codec::negotiate(&Hello::current(), &server);
+        println!(
+            "Server speaks {}, encoding: {}",
+            server.describe(),
+            codec.describe()
+        );
+
+        if let Some(room) = &self.hello.room {
+            if server.room.as_ref() != Some(room) {
+                anyhow::bail!("Server does not support rooms, refusing to join the shared one");
+            }
+            if server.room_auth {
+                let token = self
+                    .room_token
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Room '{}' requires a token", room))?;
+                tokio::time::timeout(
+                    HANDSHAKE_TIMEOUT,
+                    authenticate_to_server(&mut stream, token),
+                )
+                .await
+                .context("Timed out waiting for room authentication")??;
+            }
+            println!("Joined room {}", room);
+        }
+
+        let heartbeat = self.heartbeat.filter(|_| server.heartbeat);
+
+        let (mut read_half, write_half) = tokio::io::split(stream);
+        // Pongs are written by the send task, which owns the write half
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+
+        // Task to receive messages from server
+        let cipher = self.cipher.clone();
+        // Encrypted content is bound to the room, a relay can't move it to another one
+        let room = self.hello.room.clone();
+        let outbox = self.outbox.clone();
+        let mut receive_handle = tokio::spawn(async move {
+            let mut transfers = Transfers::new(codec, "server".to_string());
+            loop {
+                let mut message = match read_frame_timeout(&mut read_half, codec, heartbeat).await {
+                    Ok(Frame::Clipboard(message)) => message,
+                    Ok(Frame::Control(Control::Chunk(chunk))) => match transfers.receive(chunk) {
+                        Some(message) => message,
+                        None => continue,
+                    },
+                    Ok(Frame::Control(Control::Cancel { transfer_id })) => {
+                        transfers.cancel(transfer_id);
+                        continue;
+                    }
+                    Ok(Frame::Control(Control::Ping)) => {
+                        let _ = reply_tx.send(Control::Pong);
+                        continue;
+                    }
+                    Ok(Frame::Control(Control::Pong)) => continue,
+                    Ok(Frame::Control(Control::Ack { seq })) => {
+                        outbox.ack(seq);
+                        continue;
+                    }
+                    Ok(Frame::Control(Control::Hello(_))) => {
+                        eprintln!("Ignoring repeated hello from server");
+                        continue;
+                    }
+                    Err(FrameError::Undecodable(e)) => {
+                        eprintln!("Skipping undecodable message from server: {:#}", e);
+                        continue;
+                    }
+                    Err(FrameError::Closed) => {
+                        println!("Server closed connection");
+                        break;
                     }
                     Err(e) => {
-                        if e.to_string().contains("Failed to read message length") {
-                            println!("Server closed connection");
-                            break;
-                        }
                         eprintln!("Error reading from server: {}", e);
                         break;
                     }
+                };
+
+                match (&cipher, &message.content) {
+                    (Some(cipher), _) => {
+                        if let Err(e) = cipher.open(&mut message, room.as_deref()) {
+                            eprintln!("Rejected message from server: {}", e);
+                            continue;
+                        }
+                    }
+                    (None, ClipboardContent::Encrypted { .. }) => {
+                        eprintln!(
+                            "Received encrypted content but no passphrase is configured, ignoring"
+                        );
+                        continue;
+                    }
+                    (None, _) => {}
+                }
+
+                if let Err(e) = tx.send(message) {
+                    eprintln!("Failed to send received message: {}", e);
+                    break;
                 }
             }
         });
 
         // Task to send messages to server
-        let client_id = self.client_id.clone();
-        let send_handle = tokio::spawn(async move {
+        let cipher = self.cipher.clone();
+        let room = self.hello.room.clone();
+        let outbox = self.outbox.clone();
+        let mut send_handle = tokio::spawn(async move {
+            let mut writer = FrameWriter::new(write_half, codec, &server, "server".to_string());
+            let peer = server.clone();
+            let mut pending = Pending::new(rx, move |message: &ClipboardMessage| {
+                deliverable(message, &peer)
+            });
+
+            // The previous connection may have died before the server got our last copies
+            let unacked = if server.acks {
+                outbox.unacked()
+            } else {
+                Vec::new()
+            };
+            for message in unacked {
+                println!(
+                    "Retransmitting unacknowledged {} (seq {})",
+                    message.content.summary(),
+                    message.seq.unwrap_or_default()
+                );
+                if let Err(e) = writer
+                    .send(&message, &mut reply_rx, || {
+                        pending.supersedes(message.selection)
+                    })
+                    .await
+                {
+                    eprintln!("Failed to send to server: {}", e);
+                    return;
+                }
+            }
+
+            let mut ticker = heartbeat.map(|heartbeat| heartbeat.ticker());
             loop {
-                match rx.recv().await {
-                    Ok(content) => {
-                        let message = ClipboardMessage {
-                            content,
-                            timestamp: std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs(),
-                            client_id: Some(client_id.clone()),
-                        };
+                let result = tokio::select! {
+                    received = pending.recv() => match received {
+                        // The server would apply it to its clipboard
+                        Ok(message) if message.selection == Selection::Primary && !server.primary => {
+                            continue
+                        }
+                        Ok(mut message) => {
+                            let Some(content) =
+                                message.content.downgrade_for(&server.content_kinds)
+                            else {
+                                println!(
+                                    "Skipping {}: not supported by server",
+                                    message.content.summary()
+                                );
+                                continue;
+                            };
+                            message.content = content;
+                            if let Some(cipher) = &cipher {
+                                if let Err(e) = cipher.seal(&mut message, room.as_deref()) {
+                                    eprintln!("Failed to encrypt clipboard content: {}", e);
+                                    continue;
+                                }
+                            }
+                            if server.acks {
+                                outbox.track(&mut message);
+                            }
 
-                        if let Err(e) = write_message(&mut write_half, &message).await {
-                            eprintln!("Failed to send to server: {}", e);
+                            // A newer message waiting behind this one cancels a chunked transfer
+                            let selection = message.selection;
+                            writer
+                                .send(&message, &mut reply_rx, || pending.supersedes(selection))
+                                .await
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            // Skip lagged messages
+                            continue;
+                        }
+                        Err(_) => {
                             break;
                         }
-                    }
-                    Err(broadcast::error::RecvError::Lagged(_)) => {
-                        // Skip lagged messages
-                        continue;
-                    }
-                    Err(_) => {
-                        break;
-                    }
+                    },
+                    Some(reply) = reply_rx.recv() => writer.control(reply).await,
+                    _ = next_ping(&mut ticker) => writer.control(Control::Ping).await,
+                };
+                if let Err(e) = result {
+                    eprintln!("Failed to send to server: {}", e);
+                    break;
                 }
             }
         });
 
-        // Wait for either task to complete
+        // Wait for either task to complete, then tear down the other one
         tokio::select! {
-            _ = receive_handle => {},
-            _ = send_handle => {},
+            _ = &mut receive_handle => {},
+            _ = &mut send_handle => {},
         }
+        receive_handle.abort();
+        send_handle.abort();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // What current peers negotiate with each other
+    const CURRENT: Codec = Codec {
+        encoding: Encoding::MessagePack,
+        compression: Some(Compression::Zstd),
+    };
+
+    fn text(text: &str) -> ClipboardMessage {
+        ClipboardMessage::new(
+            ClipboardContent::Text(text.to_string()),
+            Some("test".into()),
+        )
+    }
+
+    fn as_text(message: &ClipboardMessage) -> &str {
+        match &message.content {
+            ClipboardContent::Text(text) => text,
+            other => panic!("expected text, got {}", other.summary()),
+        }
+    }
+
+    // Random bytes don't compress, so a message of them keeps its size on the wire
+    fn image(size: usize) -> ClipboardMessage {
+        let mut data = vec![0u8; size];
+        rand::fill(&mut data[..]);
+        let content = ClipboardContent::Image {
+            data,
+            width: 1,
+            height: 1,
+        };
+        ClipboardMessage::new(content, Some("test".into()))
+    }
+
+    fn image_data(message: &ClipboardMessage) -> &[u8] {
+        match &message.content {
+            ClipboardContent::Image { data, .. } => data,
+            other => panic!("expected an image, got {}", other.summary()),
+        }
+    }
+
+    // Every frame in `bytes`, up to the end
+    async fn read_all(mut bytes: &[u8], codec: Codec) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        loop {
+            match read_frame(&mut bytes, codec).await {
+                Ok(frame) => frames.push(frame),
+                Err(FrameError::Closed) => return frames,
+                Err(e) => panic!("unexpected frame error: {}", e),
+            }
+        }
+    }
+
+    async fn round_trip(message: &ClipboardMessage, codec: Codec) -> ClipboardMessage {
+        let mut bytes = Vec::new();
+        let frame = Frame::Clipboard(message.clone());
+        write_frame(&mut bytes, &frame, codec, true).await.unwrap();
+        match read_all(&bytes, codec).await.as_slice() {
+            [Frame::Clipboard(message)] => message.clone(),
+            frames => panic!("expected one clipboard frame, got {:?}", frames),
+        }
+    }
+
+    // Servers and clients on localhost, for tests of whole connections
+    mod harness {
+        use super::*;
+        pub(super) use tokio::task::JoinHandle;
+
+        // Long enough for clients to connect and for messages to be relayed
+        pub(super) const SETTLE: Duration = Duration::from_millis(300);
+
+        pub(super) struct Running {
+            pub(super) addr: SocketAddr,
+            pub(super) rooms: Rooms,
+            pub(super) tasks: Vec<JoinHandle<()>>,
+        }
+
+        impl Drop for Running {
+            fn drop(&mut self) {
+                self.tasks.iter().for_each(JoinHandle::abort);
+            }
+        }
+
+        pub(super) fn free_addr() -> SocketAddr {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        }
+
+        // A server relaying every message to the room it came from, like `copi server`
+        pub(super) async fn start(configure: impl FnOnce(SyncServer) -> SyncServer) -> Running {
+            let addr = free_addr();
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let server =
+                configure(SyncServer::new(addr, tx, broadcast::channel(16).0).with_heartbeat(None));
+            let rooms = server.rooms();
+            let relay = rooms.clone();
+            let tasks = vec![
+                tokio::spawn(async move {
+                    let _ = server.start().await;
+                }),
+                tokio::spawn(async move {
+                    while let Some(message) = rx.recv().await {
+                        let _ = relay.send(message);
+                    }
+                }),
+            ];
+            tokio::time::sleep(SETTLE).await;
+            Running { addr, rooms, tasks }
+        }
+
+        // Dropping it closes the copies channel, which ends the connection
+        pub(super) struct Client {
+            copies: broadcast::Sender<ClipboardMessage>,
+            received: mpsc::UnboundedReceiver<ClipboardMessage>,
+            pub(super) task: JoinHandle<Result<()>>,
+        }
+
+        impl Client {
+            pub(super) fn connect(
+                server: &Running,
+                configure: impl FnOnce(SyncClient) -> SyncClient,
+            ) -> Self {
+                let client = configure(SyncClient::new(Endpoint::Tcp(server.addr)));
+                let (copies, rx) = broadcast::channel(16);
+                let (tx, received) = mpsc::unbounded_channel();
+                let task = tokio::spawn(async move { client.connect_bidirectional(tx, rx).await });
+                Self {
+                    copies,
+                    received,
+                    task,
+                }
+            }
+
+            pub(super) fn in_room(server: &Running, room: &str) -> Self {
+                Self::connect(server, |client| client.with_room(room.to_string(), None))
+            }
+
+            pub(super) fn copy(&self, copied: &str) {
+                self.send(text(copied));
+            }
+
+            pub(super) fn send(&self, message: ClipboardMessage) {
+                self.copies.send(message).unwrap();
+            }
+
+            pub(super) async fn next(&mut self) -> String {
+                as_text(&self.next_message().await).to_string()
+            }
+
+            pub(super) async fn next_message(&mut self) -> ClipboardMessage {
+                match tokio::time::timeout(TIMEOUT, self.received.recv()).await {
+                    Ok(Some(message)) => message,
+                    _ => panic!("nothing received"),
+                }
+            }
+
+            pub(super) async fn receives_nothing(&mut self) -> bool {
+                tokio::time::timeout(SETTLE, self.received.recv())
+                    .await
+                    .is_err()
+            }
+        }
+    }
+
+    // Hellos, and peers from before them
+    mod negotiation {
+        use super::harness::*;
+        use super::*;
+
+        #[tokio::test]
+        async fn silent_clients_are_served_as_legacy_clients() {
+            let hello_timeout = Duration::from_millis(100);
+            let server =
+                start(|server| server.with_replay(false).with_hello_timeout(hello_timeout)).await;
+            let mut legacy = TcpStream::connect(server.addr).await.unwrap();
+            tokio::time::sleep(SETTLE).await;
+
+            server.rooms.send(text("for old clients")).unwrap();
+            // Plain JSON, without a compression flag
+            let message: ClipboardMessage =
+                tokio::time::timeout(TIMEOUT, read_message(&mut legacy, Codec::HANDSHAKE))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(as_text(&message), "for old clients");
+        }
+
+        #[tokio::test]
+        async fn messages_carry_the_name_from_the_hello() {
+            let server = start(|server| server).await;
+            let laptop = Identity {
+                device_id: "laptop-id".to_string(),
+                name: "laptop".to_string(),
+            };
+            let sender = Client::connect(&server, |client| client.with_identity(&laptop));
+            let mut receiver = Client::connect(&server, |client| client);
+            tokio::time::sleep(SETTLE).await;
+
+            sender.send(text("copied").with_device_name("someone else"));
+            let message = receiver.next_message().await;
+            assert_eq!(message.device_name.as_deref(), Some("laptop"));
+        }
+
+        #[tokio::test]
+        async fn clients_fall_back_to_legacy_servers() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = Running {
+                addr: listener.local_addr().unwrap(),
+                rooms: Rooms::new(broadcast::channel(1).0),
+                tasks: Vec::new(),
+            };
+            let mut client = Client::connect(&server, |client| client);
+
+            // A server from before the hello fails to parse it and drops the connection
+            let (mut first, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = first.read(&mut buffer).await;
+            drop(first);
+
+            let (mut legacy, _) = tokio::time::timeout(TIMEOUT, listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            write_message(&mut legacy, &text("from old server"), Codec::HANDSHAKE)
+                .await
+                .unwrap();
+            assert_eq!(client.next().await, "from old server");
+
+            client.copy("to old server");
+            let message: ClipboardMessage =
+                tokio::time::timeout(TIMEOUT, read_message(&mut legacy, Codec::HANDSHAKE))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(as_text(&message), "to old server");
+            // Still connected, it doesn't keep retrying the hello
+            assert!(!client.task.is_finished());
+        }
+    }
+
+    // Latest clipboard for clients that just connected
+    mod replay {
+        use super::harness::*;
+        use super::*;
+
+        #[tokio::test]
+        async fn latest_clipboard_is_replayed_on_connect() {
+            let server = start(|server| server).await;
+            let sender = Client::connect(&server, |client| client);
+            sender.copy("first");
+            sender.copy("second");
+            tokio::time::sleep(SETTLE).await;
+
+            let mut late = Client::connect(&server, |client| client);
+            assert_eq!(late.next().await, "second");
+            assert!(late.receives_nothing().await);
+        }
+
+        #[tokio::test]
+        async fn replayed_message_is_not_sent_again() {
+            let (broadcast_tx, broadcast_rx) = broadcast::channel(16);
+            let stamped = |content: &str, clock| {
+                let mut message = text(content);
+                message.clock = Some(clock);
+                message
+            };
+            // Sent after the client subscribed, and taken as the replay as well
+            let latest = stamped("latest", 1);
+            broadcast_tx.send(latest.clone()).unwrap();
+
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let (tx, _rx) = mpsc::unbounded_channel();
+            let task = tokio::spawn(SyncServer::handle_client(
+                Box::new(server),
+                tx,
+                broadcast_rx,
+                Some(latest),
+                Hello::current(),
+                None,
+                "127.0.0.1:1".parse().unwrap(),
+            ));
+            let mut next = async || match tokio::time::timeout(
+                TIMEOUT,
+                read_frame(&mut client, CURRENT),
+            )
+            .await
+            {
+                Ok(Ok(Frame::Clipboard(message))) => as_text(&message).to_string(),
+                other => panic!("expected a clipboard message, got {:?}", other),
+            };
+            assert_eq!(next().await, "latest");
+
+            broadcast_tx.send(stamped("newer", 2)).unwrap();
+            assert_eq!(next().await, "newer");
+            task.abort();
+        }
+
+        #[tokio::test]
+        async fn replay_can_be_disabled() {
+            let server = start(|server| server.with_replay(false)).await;
+            let sender = Client::connect(&server, |client| client);
+            sender.copy("copied");
+            tokio::time::sleep(SETTLE).await;
+
+            let mut late = Client::connect(&server, |client| client);
+            assert!(late.receives_nothing().await);
+        }
+    }
+
+    // Length-prefixed frames in each encoding
+    mod framing {
+        use super::*;
+
+        #[tokio::test]
+        async fn every_codec_round_trips_binary_content() {
+            let message = image(1000);
+            let codecs = [
+                Codec::HANDSHAKE,
+                Codec {
+                    encoding: Encoding::MessagePack,
+                    compression: None,
+                },
+                CURRENT,
+            ];
+            for codec in codecs {
+                let received = round_trip(&message, codec).await;
+                assert_eq!(image_data(&received), image_data(&message), "{:?}", codec);
+                assert_eq!(received.client_id.as_deref(), Some("test"));
+            }
+        }
+
+        #[test]
+        fn messagepack_carries_bytes_without_base64() {
+            let message = image(30_000);
+            let json = Codec::HANDSHAKE.encode(&message).unwrap();
+            let msgpack = CURRENT.encode(&message).unwrap();
+            assert!(json.len() > 40_000, "JSON is {} bytes", json.len());
+            assert!(
+                msgpack.len() < 30_200,
+                "MessagePack is {} bytes",
+                msgpack.len()
+            );
+        }
+
+        #[tokio::test]
+        async fn truncated_or_invalid_frames_break_the_connection() {
+            let mut bytes = Vec::new();
+            write_frame(&mut bytes, &Frame::Control(Control::Ping), CURRENT, true)
+                .await
+                .unwrap();
+            let mut reader = &bytes[..bytes.len() - 1];
+            let result = read_frame(&mut reader, CURRENT).await;
+            assert!(matches!(result, Err(FrameError::Broken(_))));
+
+            let mut reader = &0u32.to_be_bytes()[..];
+            let result = read_frame(&mut reader, CURRENT).await;
+            assert!(matches!(result, Err(FrameError::Broken(_))));
+
+            let mut reader = &[0, 0, 0, 2, 7, 0][..];
+            let result = read_frame(&mut reader, CURRENT).await;
+            assert!(matches!(result, Err(FrameError::Broken(_))));
+        }
+
+        #[tokio::test]
+        async fn close_between_frames_is_not_an_error() {
+            let mut reader = &[][..];
+            let result = read_frame(&mut reader, CURRENT).await;
+            assert!(matches!(result, Err(FrameError::Closed)));
+        }
+    }
+
+    // zstd compressed frames
+    mod compression {
+        use super::*;
+
+        #[tokio::test]
+        async fn large_text_is_compressed() {
+            let message = text(&"copy and paste ".repeat(10_000));
+            let received = round_trip(&message, CURRENT).await;
+            assert_eq!(as_text(&received), as_text(&message));
+            let (compressed, uncompressed) = received.compressed.expect("frame was not compressed");
+            assert!(compressed < uncompressed / 10);
+
+            // Below the threshold it isn't worth it
+            let received = round_trip(&text("short"), CURRENT).await;
+            assert!(received.compressed.is_none());
+        }
+
+        #[test]
+        fn mostly_png_content_is_not_compressed() {
+            let png = [PNG_SIGNATURE, &[0; 20_000]].concat();
+            let html = |len| Representation {
+                mime: HTML_MIME.to_string(),
+                data: vec![b'a'; len],
+            };
+            let image = Representation {
+                mime: PNG_MIME.to_string(),
+                data: png.clone(),
+            };
+            assert!(!ClipboardContent::Multi(vec![html(100), image.clone()]).compressible());
+            assert!(ClipboardContent::Multi(vec![html(50_000), image]).compressible());
+
+            let file = |name: &str, data: Vec<u8>| FileEntry {
+                name: name.to_string(),
+                data,
+            };
+            // Recognized by content, not by name
+            assert!(!ClipboardContent::Files(vec![file("shot", png.clone())]).compressible());
+            assert!(
+                ClipboardContent::Files(vec![file("notes.png", vec![0; 20_000])]).compressible()
+            );
+        }
+
+        #[tokio::test]
+        async fn undecodable_frames_are_skipped() {
+            let mut bytes = Vec::new();
+            write_payload(&mut bytes, &[FLAG_ZSTD, 1, 2, 3])
+                .await
+                .unwrap();
+            write_payload(&mut bytes, &[FLAG_RAW, 0xc1]).await.unwrap();
+            let frame = Frame::Clipboard(text("next"));
+            write_frame(&mut bytes, &frame, CURRENT, true)
+                .await
+                .unwrap();
+
+            let mut reader = &bytes[..];
+            for _ in 0..2 {
+                let result = read_frame(&mut reader, CURRENT).await;
+                assert!(matches!(result, Err(FrameError::Undecodable(_))));
+            }
+            let result = read_frame(&mut reader, CURRENT).await;
+            assert!(matches!(result, Ok(Frame::Clipboard(message)) if as_text(&message) == "next"));
+        }
+    }
+
+    // Messages split into chunks
+    mod transfers {
+        use super::*;
+
+        async fn send(
+            message: &ClipboardMessage,
+            peer: &Hello,
+            superseded: impl FnMut() -> bool,
+        ) -> Vec<Frame> {
+            let codec = Codec::negotiate(&Hello::current(), peer);
+            let mut writer = FrameWriter::new(Vec::new(), codec, peer, "peer".to_string());
+            let (_reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+            writer
+                .send(message, &mut reply_rx, superseded)
+                .await
+                .unwrap();
+            read_all(&writer.writer, codec).await
+        }
+
+        fn chunks(frames: Vec<Frame>) -> Vec<Chunk> {
+            frames
+                .into_iter()
+                .map(|frame| match frame {
+                    Frame::Control(Control::Chunk(chunk)) => chunk,
+                    other => panic!("expected a chunk, got {:?}", other),
+                })
+                .collect()
+        }
+
+        fn transfers() -> Transfers {
+            Transfers::new(CURRENT, "peer".to_string())
+        }
+
+        #[tokio::test]
+        async fn large_messages_are_chunked_and_reassembled() {
+            let message = image(CHUNK_SIZE * 5 / 2);
+            let chunks = chunks(send(&message, &Hello::current(), || false).await);
+            assert_eq!(chunks.len(), 3);
+
+            let mut transfers = transfers();
+            let (last, rest) = chunks.split_last().unwrap();
+            for chunk in rest {
+                assert!(transfers.receive(chunk.clone()).is_none());
+            }
+            let received = transfers
+                .receive(last.clone())
+                .expect("transfer incomplete");
+            assert_eq!(image_data(&received), image_data(&message));
+        }
+
+        #[tokio::test]
+        async fn peers_without_chunking_get_a_single_frame() {
+            let message = image(CHUNK_SIZE * 2);
+            let frames = send(&message, &Hello::legacy(), || false).await;
+            assert!(matches!(frames.as_slice(), [Frame::Clipboard(_)]));
+
+            // Unless it doesn't fit into one
+            let frames = send(&image(MAX_FRAME_SIZE), &Hello::legacy(), || false).await;
+            assert!(frames.is_empty());
+        }
+
+        #[tokio::test]
+        async fn superseded_transfers_are_cancelled() {
+            let message = image(CHUNK_SIZE * 3);
+            let frames = send(&message, &Hello::current(), || true).await;
+            let [Frame::Control(Control::Chunk(first)), Frame::Control(Control::Cancel { transfer_id })] =
+                frames.as_slice()
+            else {
+                panic!("expected a chunk and a cancel, got {:?}", frames);
+            };
+            assert_eq!(first.transfer_id, *transfer_id);
+
+            let mut transfers = transfers();
+            assert!(transfers.receive(first.clone()).is_none());
+            transfers.cancel(*transfer_id);
+            assert!(transfers.current.is_none());
+        }
+
+        #[tokio::test]
+        async fn new_transfer_replaces_the_one_in_flight() {
+            let first = chunks(send(&image(CHUNK_SIZE * 3 / 2), &Hello::current(), || false).await);
+            let message = image(CHUNK_SIZE * 3 / 2);
+            let mut second = chunks(send(&message, &Hello::current(), || false).await);
+            second.iter_mut().for_each(|chunk| chunk.transfer_id = 2);
+
+            let mut transfers = transfers();
+            assert!(transfers.receive(first[0].clone()).is_none());
+            assert!(transfers.receive(second[0].clone()).is_none());
+            // A late chunk of the replaced transfer
+            assert!(transfers.receive(first[1].clone()).is_none());
+            assert!(transfers.current.is_none());
+
+            assert!(transfers.receive(second[0].clone()).is_none());
+            let received = transfers.receive(second[1].clone()).unwrap();
+            assert_eq!(image_data(&received), image_data(&message));
+        }
+
+        #[test]
+        fn oversized_transfers_are_rejected() {
+            let mut transfers = transfers();
+            let chunk = |index, size| Chunk {
+                transfer_id: 1,
+                index,
+                total: 1000,
+                size,
+                data: vec![0; 10],
+            };
+            assert!(transfers
+                .receive(chunk(0, MAX_TRANSFER_SIZE as u64 + 1))
+                .is_none());
+            assert!(transfers.current.is_none());
+            assert!(transfers.receive(chunk(1, 0)).is_none());
+
+            // The claimed size isn't allocated before the data arrives
+            assert!(transfers
+                .receive(chunk(0, MAX_TRANSFER_SIZE as u64))
+                .is_none());
+            let transfer = transfers.current.as_ref().unwrap();
+            assert!(transfer.data.capacity() <= CHUNK_SIZE);
+        }
+
+        #[test]
+        fn chunks_out_of_order_drop_the_transfer() {
+            let mut transfers = transfers();
+            let chunk = |index| Chunk {
+                transfer_id: 1,
+                index,
+                total: 3,
+                size: CHUNK_SIZE as u64 * 3,
+                data: vec![0; CHUNK_SIZE],
+            };
+            assert!(transfers.receive(chunk(0)).is_none());
+            assert!(transfers.receive(chunk(2)).is_none());
+            assert!(transfers.current.is_none());
+        }
+    }
+
+    // Pings and dead connections
+    mod heartbeat {
+        use super::*;
+        use std::time::Instant;
+
+        const HEARTBEAT: Heartbeat = Heartbeat {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+
+        #[tokio::test]
+        async fn silent_peer_times_out() {
+            let (_peer, mut reader) = tokio::io::duplex(64);
+            let started = Instant::now();
+            let result = read_frame_timeout(&mut reader, CURRENT, Some(HEARTBEAT)).await;
+            assert!(matches!(result, Err(FrameError::TimedOut(_))));
+            assert!(started.elapsed() >= HEARTBEAT.timeout);
+        }
+
+        #[tokio::test]
+        async fn slow_frame_keeps_the_connection_alive() {
+            let mut bytes = Vec::new();
+            let frame = Frame::Clipboard(text("slowly"));
+            write_frame(&mut bytes, &frame, CURRENT, true)
+                .await
+                .unwrap();
+
+            // The whole frame takes several timeouts, but no gap is as long as one
+            let (mut peer, mut reader) = tokio::io::duplex(64);
+            tokio::spawn(async move {
+                for piece in bytes.chunks(bytes.len().div_ceil(8)) {
+                    tokio::time::sleep(HEARTBEAT.timeout / 2).await;
+                    peer.write_all(piece).await.unwrap();
+                }
+                std::future::pending::<()>().await;
+            });
+            let started = Instant::now();
+            let result = read_frame_timeout(&mut reader, CURRENT, Some(HEARTBEAT)).await;
+            assert!(
+                matches!(result, Ok(Frame::Clipboard(message)) if as_text(&message) == "slowly")
+            );
+            assert!(started.elapsed() > HEARTBEAT.timeout * 2);
+        }
+
+        #[tokio::test]
+        async fn pings_are_answered_between_chunks() {
+            let mut writer =
+                FrameWriter::new(Vec::new(), CURRENT, &Hello::current(), "peer".into());
+            let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+            // The peer pings while the first chunks are on their way
+            let mut checks = 0;
+            writer
+                .send(&image(CHUNK_SIZE * 5 / 2), &mut reply_rx, || {
+                    checks += 1;
+                    if checks == 1 {
+                        reply_tx.send(Control::Pong).unwrap();
+                    }
+                    false
+                })
+                .await
+                .unwrap();
+
+            let frames = read_all(&writer.writer, CURRENT).await;
+            let kinds: Vec<&str> = frames
+                .iter()
+                .map(|frame| match frame {
+                    Frame::Control(Control::Chunk(_)) => "chunk",
+                    Frame::Control(Control::Pong) => "pong",
+                    other => panic!("unexpected frame {:?}", other),
+                })
+                .collect();
+            assert_eq!(kinds, ["chunk", "chunk", "pong", "chunk"]);
+        }
+    }
+
+    // Acknowledgements and retransmission
+    mod outbox {
+        use super::*;
+
+        fn seqs(outbox: &Outbox) -> Vec<u64> {
+            outbox
+                .unacked()
+                .iter()
+                .filter_map(|message| message.seq)
+                .collect()
+        }
+
+        #[test]
+        fn ack_covers_earlier_messages() {
+            let outbox = Outbox::new();
+            let mut clipboard = text("clipboard");
+            let mut primary = text("primary").with_selection(Selection::Primary);
+            outbox.track(&mut clipboard);
+            outbox.track(&mut primary);
+            assert_eq!((clipboard.seq, primary.seq), (Some(1), Some(2)));
+            assert_eq!(seqs(&outbox), [1, 2]);
+
+            outbox.ack(1);
+            assert_eq!(seqs(&outbox), [2]);
+            outbox.ack(2);
+            assert!(outbox.unacked().is_empty());
+        }
+
+        #[test]
+        fn only_the_latest_message_per_selection_is_kept() {
+            let outbox = Outbox::new();
+            for copied in ["first", "second"] {
+                outbox.track(&mut text(copied));
+            }
+            outbox.track(&mut text("selected").with_selection(Selection::Primary));
+            outbox.track(&mut text("third"));
+
+            let unacked = outbox.unacked();
+            let texts: Vec<&str> = unacked.iter().map(as_text).collect();
+            // Oldest first
+            assert_eq!(texts, ["selected", "third"]);
+            assert_eq!(seqs(&outbox), [3, 4]);
+        }
+
+        // Plays a current server that reads one message per connection and then drops it
+        // without acknowledging anything
+        async fn forgetful_server(listener: &TcpListener) -> ClipboardMessage {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let hello = read_message::<Frame, _>(&mut stream, Codec::HANDSHAKE).await;
+            assert!(matches!(hello, Ok(Frame::Control(Control::Hello(_)))));
+            let hello = Frame::Control(Control::Hello(Hello::current()));
+            write_message(&mut stream, &hello, Codec::HANDSHAKE)
+                .await
+                .unwrap();
+            match read_frame(&mut stream, CURRENT).await {
+                Ok(Frame::Clipboard(message)) => message,
+                other => panic!("expected a clipboard message, got {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn unacknowledged_messages_are_sent_again_after_reconnecting() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client =
+                SyncClient::new(Endpoint::Tcp(listener.local_addr().unwrap())).with_heartbeat(None);
+            let outbox = client.outbox.clone();
+
+            let (copy_tx, copies) = broadcast::channel(16);
+            let (tx, _rx) = mpsc::unbounded_channel();
+            let resubscribe = copy_tx.clone();
+            let reconnecting = tokio::spawn(async move {
+                let _ = client.connect_bidirectional(tx.clone(), copies).await;
+                let _ = client
+                    .connect_bidirectional(tx, resubscribe.subscribe())
+                    .await;
+            });
+            copy_tx.send(text("copied")).unwrap();
+
+            let first = tokio::time::timeout(TIMEOUT, forgetful_server(&listener))
+                .await
+                .unwrap();
+            let again = tokio::time::timeout(TIMEOUT, forgetful_server(&listener))
+                .await
+                .unwrap();
+            assert_eq!(as_text(&again), as_text(&first));
+            assert_eq!(again.seq, first.seq);
+            assert_eq!(seqs(&outbox), [1]);
+            reconnecting.abort();
+        }
+    }
+
+    // Messages never go back to the connection they came from
+    mod echo {
+        use super::harness::*;
+        use super::*;
+
+        #[tokio::test]
+        async fn messages_are_not_echoed_to_their_sender() {
+            let server = start(|server| server.with_replay(false)).await;
+            let mut sender = Client::connect(&server, |client| client);
+            let mut other = Client::connect(&server, |client| client);
+            tokio::time::sleep(SETTLE).await;
+
+            sender.copy("copied");
+            assert_eq!(other.next().await, "copied");
+            assert!(sender.receives_nothing().await);
+        }
+
+        #[tokio::test]
+        async fn early_copies_of_legacy_clients_are_not_echoed() {
+            let server = start(|server| server.with_replay(false)).await;
+            let mut other = Client::connect(&server, |client| client);
+            tokio::time::sleep(SETTLE).await;
+
+            // Sent before the server gave up waiting for a hello
+            let mut legacy = TcpStream::connect(server.addr).await.unwrap();
+            write_message(&mut legacy, &text("copied"), Codec::HANDSHAKE)
+                .await
+                .unwrap();
+            assert_eq!(other.next().await, "copied");
+            let echoed = tokio::time::timeout(
+                SETTLE,
+                read_message::<ClipboardMessage, _>(&mut legacy, Codec::HANDSHAKE),
+            )
+            .await;
+            assert!(echoed.is_err());
+        }
+    }
+
+    // Isolated sync groups on one server
+    mod rooms {
+        use super::harness::*;
+        use super::*;
+
+        #[tokio::test]
+        async fn rooms_are_isolated() {
+            let server = start(|server| server.with_replay(false)).await;
+            let sender = Client::in_room(&server, "a");
+            let mut same_room = Client::in_room(&server, "a");
+            let mut other_room = Client::in_room(&server, "b");
+            let mut shared = Client::connect(&server, |client| client);
+            tokio::time::sleep(SETTLE).await;
+
+            sender.copy("room a");
+            assert_eq!(same_room.next().await, "room a");
+            assert!(other_room.receives_nothing().await);
+            assert!(shared.receives_nothing().await);
+
+            shared.copy("shared");
+            assert!(same_room.receives_nothing().await);
+        }
+
+        #[tokio::test]
+        async fn rooms_with_a_token_need_it() {
+            let token = AuthToken::new("room secret").unwrap();
+            let server = start(|server| server.with_room_token("private".into(), token)).await;
+
+            let without = Client::in_room(&server, "private");
+            let result = tokio::time::timeout(TIMEOUT, without.task).await.unwrap();
+            assert!(result.unwrap().is_err());
+
+            let wrong = Client::connect(&server, |client| {
+                client.with_room("private".into(), Some(AuthToken::new("guess").unwrap()))
+            });
+            let result = tokio::time::timeout(TIMEOUT, wrong.task).await.unwrap();
+            assert!(result.unwrap().is_err());
+
+            let member = || {
+                let token = AuthToken::new("room secret").unwrap();
+                Client::connect(&server, |client| {
+                    client.with_room("private".into(), Some(token))
+                })
+            };
+            let (sender, mut receiver) = (member(), member());
+            tokio::time::sleep(SETTLE).await;
+            sender.copy("secret");
+            assert_eq!(receiver.next().await, "secret");
+        }
+
+        #[tokio::test]
+        async fn rooms_are_removed_when_their_last_client_leaves() {
+            let server = start(|server| server).await;
+            let first = Client::in_room(&server, "a");
+            let second = Client::in_room(&server, "a");
+            tokio::time::sleep(SETTLE).await;
+            let members = || server.rooms.named.lock().unwrap().get("a").map(|(_, n)| *n);
+            assert_eq!(members(), Some(2));
+
+            drop(first);
+            tokio::time::sleep(SETTLE).await;
+            assert_eq!(members(), Some(1));
+            drop(second);
+            tokio::time::sleep(SETTLE).await;
+            assert!(server.rooms.named.lock().unwrap().is_empty());
+        }
+
+        #[test]
+        fn room_names_and_numbers_are_capped() {
+            let mut rooms = Rooms::new(broadcast::channel(1).0);
+            rooms.replay = false;
+            assert!(rooms.join(&"x".repeat(MAX_ROOM_NAME + 1)).is_err());
+
+            let memberships: Vec<_> = (0..MAX_ROOMS)
+                .map(|room| rooms.join(&room.to_string()).unwrap())
+                .collect();
+            assert!(rooms.join("one too many").is_err());
+            // Existing rooms can still be joined
+            assert!(rooms.join("0").is_ok());
+
+            drop(memberships);
+            assert!(rooms.join("one too many").is_ok());
+        }
+    }
+
+    // Clients connecting over WebSocket
+    mod websocket {
+        use super::harness::*;
+        use super::*;
+
+        #[tokio::test]
+        async fn websocket_clients_share_the_clipboard_with_tcp_clients() {
+            let websocket = free_addr();
+            let server = start(|server| server.with_websocket(websocket)).await;
+            let url = WebSocketUrl::parse(&format!("ws://{}/", websocket)).unwrap();
+            let websocket_client =
+                Client::connect(&server, |_| SyncClient::new(Endpoint::WebSocket(url)));
+            let mut tcp = Client::connect(&server, |client| client);
+            tokio::time::sleep(SETTLE).await;
+
+            websocket_client.copy("over websocket");
+            assert_eq!(tcp.next().await, "over websocket");
+        }
+    }
+
+    // Newer messages superseding a transfer
+    mod pending {
+        use super::*;
+
+        #[tokio::test]
+        async fn only_deliverable_messages_of_the_same_selection_supersede() {
+            let (tx, rx) = broadcast::channel(16);
+            let mut pending = Pending::new(rx, |message: &ClipboardMessage| {
+                message.client_id.as_deref() != Some("self")
+            });
+
+            tx.send(text("primary").with_selection(Selection::Primary))
+                .unwrap();
+            assert!(!pending.supersedes(Selection::Clipboard));
+            let mut own = text("own");
+            own.client_id = Some("self".into());
+            tx.send(own).unwrap();
+            assert!(!pending.supersedes(Selection::Clipboard));
+            tx.send(text("newer")).unwrap();
+            assert!(pending.supersedes(Selection::Clipboard));
+
+            // Everything taken out early is still handed out, in order
+            for expected in ["primary", "own", "newer"] {
+                assert_eq!(as_text(&pending.recv().await.unwrap()), expected);
+            }
+        }
+    }
+}
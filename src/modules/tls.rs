@@ -0,0 +1,283 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// SHA-256 fingerprint of a certificate, formatted like `openssl x509 -fingerprint -sha256`
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+/// Default location of the auto-generated self-signed certificate and key
+fn default_cert_paths() -> Result<(PathBuf, PathBuf)> {
+    let dir = dirs::config_dir()
+        .ok_or_else(|| anyhow::anyhow!("Cannot determine config directory"))?
+        .join("copi")
+        .join("tls");
+    Ok((dir.join("cert.pem"), dir.join("key.pem")))
+}
+
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> Result<()> {
+    let mut names = vec!["localhost".to_string()];
+    if let Ok(hostname) = std::env::var("HOSTNAME") {
+        names.push(hostname);
+    }
+    let certified = rcgen::generate_simple_self_signed(names)?;
+
+    if let Some(parent) = cert_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(cert_path, certified.cert.pem())?;
+    write_private(key_path, certified.key_pair.serialize_pem().as_bytes())?;
+
+    println!(
+        "Generated self-signed certificate at {}",
+        cert_path.display()
+    );
+    Ok(())
+}
+
+// The key is only ever readable by the owner, also while it is being written
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // mode() only applies to new files, an existing key may have been readable
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Build the server side TLS acceptor.
+///
+/// Without explicit paths a self-signed certificate is generated on first run and
+/// reused afterwards. Returns the acceptor together with the certificate fingerprint
+/// that clients should pin.
+pub fn server_acceptor(
+    cert_path: Option<&Path>,
+    key_path: Option<&Path>,
+) -> Result<(TlsAcceptor, String)> {
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (Some(cert), Some(key)) => (cert.to_path_buf(), key.to_path_buf()),
+        (None, None) => {
+            let (cert, key) = default_cert_paths()?;
+            if !cert.exists() || !key.exists() {
+                generate_self_signed(&cert, &key)?;
+            }
+            (cert, key)
+        }
+        _ => anyhow::bail!("Both certificate and key paths must be provided"),
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .with_context(|| format!("Failed to open certificate {}", cert_path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse certificate")?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .with_context(|| format!("Failed to load private key {}", key_path.display()))?;
+
+    let leaf = certs
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No certificate found in {}", cert_path.display()))?;
+    let fingerprint = fingerprint(leaf);
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+
+    Ok((TlsAcceptor::from(Arc::new(config)), fingerprint))
+}
+
+/// Build the client side TLS connector.
+///
+/// The server is verified either against a CA certificate (standard PKI validation)
+/// or by comparing its certificate with a pinned SHA-256 fingerprint.
+pub fn client_connector(ca_path: Option<&Path>, pinned: Option<&str>) -> Result<TlsConnector> {
+    let builder =
+        ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+
+    let config = match (ca_path, pinned) {
+        (_, Some(fingerprint)) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint: normalize_fingerprint(fingerprint),
+                provider: provider(),
+            }))
+            .with_no_client_auth(),
+        (Some(ca_path), None) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca_path)
+                .with_context(|| format!("Failed to open CA certificate {}", ca_path.display()))?
+            {
+                roots.add(cert.context("Failed to parse CA certificate")?)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        (None, None) => anyhow::bail!(
+            "TLS requires --tls-ca or --tls-fingerprint (the server prints its fingerprint on startup)"
+        ),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Accepts exactly the server certificate whose fingerprint was pinned, which makes
+/// self-signed certificates usable without a CA.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let actual = normalize_fingerprint(&fingerprint(end_entity));
+        if actual == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(tokio_rustls::rustls::Error::General(format!(
+                "Server certificate fingerprint mismatch: {}",
+                fingerprint(end_entity)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate() -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("copi-test-{:x}", rand::random::<u64>()));
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        generate_self_signed(&cert, &key).unwrap();
+        (cert, key)
+    }
+
+    // Runs both sides of a handshake, returns whether the client accepted the server
+    async fn handshake(acceptor: TlsAcceptor, connector: TlsConnector) -> bool {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let (_, client) = tokio::join!(
+            acceptor.accept(server_io),
+            connector.connect(server_name, client_io)
+        );
+        client.is_ok()
+    }
+
+    #[tokio::test]
+    async fn pinned_fingerprint_is_accepted() {
+        let (cert, key) = certificate();
+        let (acceptor, fingerprint) = server_acceptor(Some(&cert), Some(&key)).unwrap();
+
+        // Any spelling of the fingerprint works, e.g. copied without colons
+        let pinned = fingerprint.replace(':', "").to_lowercase();
+        let connector = client_connector(None, Some(&pinned)).unwrap();
+        assert!(handshake(acceptor, connector).await);
+    }
+
+    #[tokio::test]
+    async fn other_certificate_is_rejected() {
+        let (cert, key) = certificate();
+        let (acceptor, _) = server_acceptor(Some(&cert), Some(&key)).unwrap();
+
+        let (other, other_key) = certificate();
+        let (_, fingerprint) = server_acceptor(Some(&other), Some(&other_key)).unwrap();
+        let connector = client_connector(None, Some(&fingerprint)).unwrap();
+        assert!(!handshake(acceptor, connector).await);
+    }
+
+    #[test]
+    fn connector_needs_a_way_to_verify_the_server() {
+        assert!(client_connector(None, None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn generated_key_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_, key) = certificate();
+        let mode = std::fs::metadata(&key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,314 @@
+use crate::modules::sync::Selection;
+use anyhow::Result;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Fallback when no change notification mechanism is available
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub type ChangeSender = mpsc::UnboundedSender<()>;
+
+/// Watcher tasks of one monitor, one per selection
+pub struct WatchHandle(pub Vec<JoinHandle<()>>);
+
+impl WatchHandle {
+    pub fn abort(&self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+pub fn spawn_poller(tx: ChangeSender, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(poll(tx, interval))
+}
+
+async fn poll(tx: ChangeSender, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = tx.send(()) {
+            eprintln!("Monitor channel closed: {}", e);
+            break;
+        }
+    }
+}
+
+/// A watch loop that blocks on its event source and has to run on a thread of its own.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+trait ThreadWatch: Send + Sync + 'static {
+    /// Sends a notification per change until the channel closes or `stop` is called
+    fn run(&self, tx: &ChangeSender) -> Result<()>;
+
+    /// Makes `run` return soon, called from another thread
+    fn stop(&self);
+}
+
+// Stops the watch thread when the task waiting for it is aborted or dropped at shutdown
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+struct StopOnDrop<W: ThreadWatch>(std::sync::Arc<W>);
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl<W: ThreadWatch> Drop for StopOnDrop<W> {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+/// Runs a blocking watch loop on a dedicated thread and falls back to polling if it fails.
+///
+/// The thread is detached, so it never holds up runtime shutdown; aborting the returned
+/// task stops it.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn spawn_thread_watcher<W, F>(
+    name: &'static str,
+    tx: ChangeSender,
+    poll_interval: Duration,
+    open: F,
+) -> JoinHandle<()>
+where
+    W: ThreadWatch,
+    F: FnOnce() -> Result<W> + Send + 'static,
+{
+    tokio::spawn(async move {
+        match watch_on_thread(name, tx.clone(), open).await {
+            Ok(()) => return,
+            Err(e) => eprintln!("{} unavailable ({}), falling back to polling", name, e),
+        }
+        poll(tx, poll_interval).await;
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn watch_on_thread<W, F>(name: &'static str, tx: ChangeSender, open: F) -> Result<()>
+where
+    W: ThreadWatch,
+    F: FnOnce() -> Result<W> + Send + 'static,
+{
+    use anyhow::Context;
+    use std::sync::Arc;
+
+    let watch = Arc::new(tokio::task::spawn_blocking(open).await??);
+    let _stop = StopOnDrop(watch.clone());
+
+    let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name(format!("{} watcher", name))
+        .spawn(move || {
+            let _ = done_tx.send(watch.run(&tx));
+        })?;
+    done_rx.await.context("watch thread panicked")?
+}
+
+/// Change notifications from `wl-paste --watch` (wlroots data-control protocol).
+#[cfg(target_os = "linux")]
+pub fn spawn_wl_paste_watcher(
+    tx: ChangeSender,
+    poll_interval: Duration,
+    selection: Selection,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        match wl_paste_watch(&tx, selection).await {
+            Ok(()) => return,
+            Err(e) => eprintln!(
+                "wl-paste --watch unavailable ({}), falling back to polling",
+                e
+            ),
+        }
+        poll(tx, poll_interval).await;
+    })
+}
+
+#[cfg(target_os = "linux")]
+async fn wl_paste_watch(tx: &ChangeSender, selection: Selection) -> Result<()> {
+    use anyhow::Context;
+    use std::process::Stdio;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let mut command = tokio::process::Command::new("wl-paste");
+    if selection == Selection::Primary {
+        command.arg("--primary");
+    }
+    // wl-paste runs `echo` on every change, so each output line is one notification
+    let mut child = command
+        .arg("--watch")
+        .arg("echo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().context("wl-paste has no stdout")?;
+
+    println!("Watching {} with wl-paste --watch", selection);
+    let mut lines = BufReader::new(stdout).lines();
+    while lines.next_line().await?.is_some() {
+        if tx.send(()).is_err() {
+            return Ok(());
+        }
+    }
+
+    let status = child.wait().await?;
+    anyhow::bail!("wl-paste exited with {}", status)
+}
+
+/// Change notifications for the native clipboard used by arboard.
+///
+/// Only X11 has a PRIMARY selection, elsewhere `selection` is always the clipboard.
+pub fn spawn_native_watcher(
+    tx: ChangeSender,
+    poll_interval: Duration,
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))] selection: Selection,
+) -> JoinHandle<()> {
+    #[cfg(target_os = "macos")]
+    return spawn_thread_watcher("NSPasteboard", tx, poll_interval, || {
+        Ok(ChangeCountWatch::default())
+    });
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        #[cfg(target_os = "linux")]
+        if std::env::var("DISPLAY").is_ok() {
+            return spawn_thread_watcher("XFixes", tx, poll_interval, move || {
+                XfixesWatch::open(selection)
+            });
+        }
+
+        spawn_poller(tx, poll_interval)
+    }
+}
+
+/// Waits for XFixes selection owner events on CLIPBOARD or PRIMARY.
+#[cfg(target_os = "linux")]
+struct XfixesWatch {
+    conn: x11rb::rust_connection::RustConnection,
+    selection: Selection,
+    // Our own window, stop() sends an event to it to wake up wait_for_event()
+    window: u32,
+    stopped: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(target_os = "linux")]
+impl XfixesWatch {
+    fn open(selection: Selection) -> Result<Self> {
+        use x11rb::connection::Connection;
+        use x11rb::protocol::xfixes::{ConnectionExt as _, SelectionEventMask};
+        use x11rb::protocol::xproto::{ConnectionExt as _, CreateWindowAux, WindowClass};
+
+        let (conn, screen_num) = x11rb::connect(None)?;
+        conn.xfixes_query_version(5, 0)?.reply()?;
+
+        let root = conn.setup().roots[screen_num].root;
+        let name: &[u8] = match selection {
+            Selection::Clipboard => b"CLIPBOARD",
+            Selection::Primary => b"PRIMARY",
+        };
+        let atom = conn.intern_atom(false, name)?.reply()?.atom;
+        conn.xfixes_select_selection_input(
+            root,
+            atom,
+            SelectionEventMask::SET_SELECTION_OWNER
+                | SelectionEventMask::SELECTION_WINDOW_DESTROY
+                | SelectionEventMask::SELECTION_CLIENT_CLOSE,
+        )?;
+
+        let window = conn.generate_id()?;
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::new(),
+        )?;
+        conn.flush()?;
+
+        Ok(Self {
+            conn,
+            selection,
+            window,
+            stopped: Default::default(),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ThreadWatch for XfixesWatch {
+    fn run(&self, tx: &ChangeSender) -> Result<()> {
+        use std::sync::atomic::Ordering;
+        use x11rb::connection::Connection;
+        use x11rb::protocol::Event;
+
+        println!("Watching {} with XFixes selection events", self.selection);
+        loop {
+            let event = self.conn.wait_for_event()?;
+            if self.stopped.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if let Event::XfixesSelectionNotify(_) = event {
+                if tx.send(()).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn stop(&self) {
+        use std::sync::atomic::Ordering;
+        use x11rb::connection::Connection;
+        use x11rb::protocol::xproto::{
+            AtomEnum, ClientMessageEvent, ConnectionExt as _, EventMask,
+        };
+
+        self.stopped.store(true, Ordering::SeqCst);
+        // Without an event mask, the event goes to the client that created the window
+        let event = ClientMessageEvent::new(32, self.window, AtomEnum::NONE, [0u32; 5]);
+        let _ = self
+            .conn
+            .send_event(false, self.window, EventMask::NO_EVENT, event);
+        let _ = self.conn.flush();
+    }
+}
+
+/// NSPasteboard has no change notification; its changeCount is cheap to read,
+/// unlike the clipboard content itself.
+#[cfg(target_os = "macos")]
+#[derive(Default)]
+struct ChangeCountWatch {
+    stopped: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(target_os = "macos")]
+impl ThreadWatch for ChangeCountWatch {
+    fn run(&self, tx: &ChangeSender) -> Result<()> {
+        use objc2_app_kit::NSPasteboard;
+        use std::sync::atomic::Ordering;
+
+        const CHANGE_COUNT_INTERVAL: Duration = Duration::from_millis(250);
+
+        let pasteboard = NSPasteboard::generalPasteboard();
+        let mut last_count = pasteboard.changeCount();
+
+        println!("Watching clipboard with NSPasteboard.changeCount");
+        while !self.stopped.load(Ordering::SeqCst) {
+            std::thread::sleep(CHANGE_COUNT_INTERVAL);
+            let count = pasteboard.changeCount();
+            if count != last_count {
+                last_count = count;
+                if tx.send(()).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.stopped
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+}